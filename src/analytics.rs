@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+
+use chrono::{NaiveTime, Timelike};
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    error::AppError,
+    fetch::{fetch_station_schedule, Upstream},
+    line::TrainLine,
+    model::StationSchedule,
+    station::Station,
+};
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct HeadwayStats {
+    /// Minutes between consecutive departures.
+    pub min: i64,
+    pub median: f32,
+    pub max: i64,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct HourlyFrequency {
+    pub hour: u32,
+    pub trains: usize,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct DestinationService {
+    /// Destination as named in the upstream route name.
+    pub destination: String,
+    pub first_train: StationSchedule,
+    pub last_train: StationSchedule,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct HeadwayAnalytics {
+    pub station: Station,
+    pub line: Option<TrainLine>,
+    pub direction: Option<Station>,
+    pub time_from: NaiveTime,
+    pub time_to: NaiveTime,
+    pub train_count: usize,
+    /// Absent when fewer than two trains match.
+    pub headway: Option<HeadwayStats>,
+    pub trains_per_hour: Vec<HourlyFrequency>,
+    pub destinations: Vec<DestinationService>,
+}
+
+/// Splits an upstream route name such as `BOGOR-JAKARTAKOTA` into its origin
+/// and destination.
+fn route_ends(route_name: &str) -> (&str, &str) {
+    match route_name.rsplit_once('-') {
+        Some((origin, destination)) => (origin.trim(), destination.trim()),
+        None => ("", route_name.trim()),
+    }
+}
+
+/// The line a train calls at `station` on, judged by the lines its origin
+/// and destination share with the station.
+fn service_line(station: Station, route_name: &str) -> Option<TrainLine> {
+    let (origin, destination) = route_ends(route_name);
+    let destination = Station::from_name(destination)?;
+    let origin = Station::from_name(origin);
+    station.line().into_iter().find(|line| {
+        destination.line().contains(line)
+            && origin.is_none_or(|origin| origin.line().contains(line))
+    })
+}
+
+fn headway_stats(departures: &[NaiveTime]) -> Option<HeadwayStats> {
+    let mut gaps = departures
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).num_minutes())
+        .collect::<Vec<_>>();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort();
+    let middle = gaps.len() / 2;
+    let median = if gaps.len() % 2 == 0 {
+        (gaps[middle - 1] + gaps[middle]) as f32 / 2.
+    } else {
+        gaps[middle] as f32
+    };
+    Some(HeadwayStats {
+        min: gaps[0],
+        median,
+        max: gaps[gaps.len() - 1],
+    })
+}
+
+pub fn analyse_headways(
+    station: Station,
+    line: Option<TrainLine>,
+    direction: Option<Station>,
+    time_from: NaiveTime,
+    time_to: NaiveTime,
+    schedules: Vec<StationSchedule>,
+) -> HeadwayAnalytics {
+    let mut trains = schedules
+        .into_iter()
+        .filter(|schedule| schedule.time_est >= time_from && schedule.time_est <= time_to)
+        .filter(|schedule| match line {
+            Some(line) => service_line(station, &schedule.route_name) == Some(line),
+            None => true,
+        })
+        .filter(|schedule| match direction {
+            Some(direction) => route_ends(&schedule.route_name).1 == direction.name(),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    trains.sort_by_key(|schedule| schedule.time_est);
+
+    let departures = trains
+        .iter()
+        .map(|schedule| schedule.time_est)
+        .collect::<Vec<_>>();
+    let trains_per_hour = (time_from.hour()..=time_to.hour())
+        .map(|hour| HourlyFrequency {
+            hour,
+            trains: departures.iter().filter(|time| time.hour() == hour).count(),
+        })
+        .collect();
+
+    let mut destinations: BTreeMap<String, (StationSchedule, StationSchedule)> = BTreeMap::new();
+    for schedule in &trains {
+        let destination = route_ends(&schedule.route_name).1.to_string();
+        destinations
+            .entry(destination)
+            .and_modify(|(_, last)| *last = schedule.clone())
+            .or_insert_with(|| (schedule.clone(), schedule.clone()));
+    }
+
+    HeadwayAnalytics {
+        station,
+        line,
+        direction,
+        time_from,
+        time_to,
+        train_count: trains.len(),
+        headway: headway_stats(&departures),
+        trains_per_hour,
+        destinations: destinations
+            .into_iter()
+            .map(
+                |(destination, (first_train, last_train))| DestinationService {
+                    destination,
+                    first_train,
+                    last_train,
+                },
+            )
+            .collect(),
+    }
+}
+
+pub async fn fetch_headways(
+    upstream: &Upstream,
+    station: Station,
+    line: Option<TrainLine>,
+    direction: Option<Station>,
+    time_from: NaiveTime,
+    time_to: NaiveTime,
+) -> Result<HeadwayAnalytics, AppError> {
+    let schedules = fetch_station_schedule(upstream, station, time_from, time_to).await?;
+    Ok(analyse_headways(
+        station, line, direction, time_from, time_to, schedules,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::time;
+
+    fn departure(train_id: &str, route_name: &str, time_est: &str) -> StationSchedule {
+        StationSchedule {
+            train_id: train_id.into(),
+            route_name: route_name.into(),
+            time_est: time(time_est),
+        }
+    }
+
+    #[test]
+    fn headways_need_two_departures() {
+        assert!(headway_stats(&[]).is_none());
+        assert!(headway_stats(&[time("06:00")]).is_none());
+    }
+
+    #[test]
+    fn headway_median_of_odd_and_even_gaps() {
+        let stats = headway_stats(&[time("06:00"), time("06:05"), time("06:20")]).unwrap();
+        assert_eq!((stats.min, stats.median, stats.max), (5, 10., 15));
+
+        let stats =
+            headway_stats(&[time("06:00"), time("06:05"), time("06:20"), time("06:24")]).unwrap();
+        assert_eq!((stats.min, stats.median, stats.max), (4, 5., 15));
+    }
+
+    #[test]
+    fn analysis_keeps_the_window_line_and_direction() {
+        let schedules = vec![
+            departure("1", "BOGOR-JAKARTAKOTA", "06:10"),
+            departure("2", "CIKARANG-KAMPUNGBANDAN", "06:05"),
+            departure("3", "BOGOR-JAKARTAKOTA", "06:00"),
+            departure("4", "JAKARTAKOTA-BOGOR", "06:20"),
+            departure("5", "BOGOR-JAKARTAKOTA", "08:00"),
+        ];
+
+        let analytics = analyse_headways(
+            Station::MRI,
+            Some(TrainLine::B),
+            Some(Station::JAKK),
+            time("06:00"),
+            time("07:00"),
+            schedules.clone(),
+        );
+        assert_eq!(analytics.train_count, 2);
+        let headway = analytics.headway.unwrap();
+        assert_eq!((headway.min, headway.max), (10, 10));
+        assert_eq!(analytics.destinations.len(), 1);
+        assert_eq!(analytics.destinations[0].first_train.train_id, "3");
+        assert_eq!(analytics.destinations[0].last_train.train_id, "1");
+
+        let analytics = analyse_headways(
+            Station::MRI,
+            None,
+            None,
+            time("06:00"),
+            time("07:00"),
+            schedules,
+        );
+        assert_eq!(analytics.train_count, 4);
+        let hours = analytics
+            .trains_per_hour
+            .iter()
+            .map(|frequency| (frequency.hour, frequency.trains))
+            .collect::<Vec<_>>();
+        assert_eq!(hours, vec![(6, 4), (7, 0)]);
+        let destinations = analytics
+            .destinations
+            .iter()
+            .map(|service| service.destination.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(destinations, vec!["BOGOR", "JAKARTAKOTA", "KAMPUNGBANDAN"]);
+    }
+}
@@ -0,0 +1,206 @@
+use actix_web::ResponseError;
+use chrono::NaiveTime;
+use futures::{stream, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::{
+    error::{parse_param, AppError, AppErrorResponse, AppErrorType},
+    fetch::{
+        fetch_distance, fetch_fare, fetch_station_schedule_with_warnings,
+        fetch_train_schedule_with_warnings, Upstream,
+    },
+    model::{StationScheduleList, TrainScheduleList},
+};
+
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// One sub-query of a batch, mirroring the query parameters of the endpoint
+/// of the same name.
+#[derive(Deserialize, ToSchema, Debug, Clone)]
+#[serde(
+    tag = "type",
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case"
+)]
+pub enum BatchQuery {
+    StationSchedule {
+        station: String,
+        time_from: Option<NaiveTime>,
+        time_to: Option<NaiveTime>,
+    },
+    TrainSchedule {
+        train_id: String,
+    },
+    Fare {
+        station_from: String,
+        station_to: String,
+    },
+    Distance {
+        station_from: String,
+        station_to: String,
+    },
+}
+
+/// Outcome of one sub-query: the HTTP status it would have had on its own,
+/// and either its body, warnings included, or its error.
+#[derive(Serialize, ToSchema)]
+pub struct BatchResult {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<AppErrorResponse>,
+}
+
+/// Decodes one sub-query on its own, so a malformed one fails in its own
+/// slot instead of rejecting the whole batch.
+fn decode_query(query: Value) -> Result<BatchQuery, AppError> {
+    serde_json::from_value(query).map_err(|err| AppError {
+        message: Some(format!("Invalid batch query: {}", err)),
+        cause: None,
+        error_type: AppErrorType::InvalidRequestParameter,
+        parameter: None,
+    })
+}
+
+fn encode_body(body: impl serde::Serialize) -> Result<Value, AppError> {
+    serde_json::to_value(body).map_err(|err| AppError {
+        message: Some("Failed to encode the result".into()),
+        cause: Some(err.to_string()),
+        error_type: AppErrorType::InternalError,
+        parameter: None,
+    })
+}
+
+async fn run_query(upstream: &Upstream, query: Value) -> Result<Value, AppError> {
+    match decode_query(query)? {
+        BatchQuery::StationSchedule {
+            station,
+            time_from,
+            time_to,
+        } => {
+            let station = parse_param(&station, "station")?;
+            let time_from = time_from.unwrap_or(NaiveTime::MIN);
+            let time_to = time_to.unwrap_or_else(|| NaiveTime::from_hms_opt(23, 59, 59).unwrap());
+            let (schedules, warnings) =
+                fetch_station_schedule_with_warnings(upstream, station, time_from, time_to).await?;
+            encode_body(StationScheduleList {
+                schedules,
+                warnings,
+            })
+        }
+        BatchQuery::TrainSchedule { train_id } => {
+            let (schedules, warnings) =
+                fetch_train_schedule_with_warnings(upstream, &train_id).await?;
+            encode_body(TrainScheduleList {
+                schedules,
+                warnings,
+            })
+        }
+        BatchQuery::Fare {
+            station_from,
+            station_to,
+        } => {
+            let station_from = parse_param(&station_from, "station-from")?;
+            let station_to = parse_param(&station_to, "station-to")?;
+            encode_body(fetch_fare(upstream, station_from, station_to).await?)
+        }
+        BatchQuery::Distance {
+            station_from,
+            station_to,
+        } => {
+            let station_from = parse_param(&station_from, "station-from")?;
+            let station_to = parse_param(&station_to, "station-to")?;
+            encode_body(fetch_distance(upstream, station_from, station_to).await?)
+        }
+    }
+}
+
+/// Runs every sub-query through the fetch layer, at most `concurrency` at a
+/// time, returning results in request order.
+pub async fn run_batch(
+    upstream: &Upstream,
+    queries: Vec<Value>,
+    concurrency: usize,
+) -> Vec<BatchResult> {
+    stream::iter(queries)
+        .map(|query| async move {
+            match run_query(upstream, query).await {
+                Ok(body) => BatchResult {
+                    status: 200,
+                    body: Some(body),
+                    error: None,
+                },
+                Err(err) => BatchResult {
+                    status: err.status_code().as_u16(),
+                    body: None,
+                    error: Some(AppErrorResponse::from(&err)),
+                },
+            }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::store::Store;
+
+    #[test]
+    fn decode_query_reads_kebab_case_fields() {
+        let query = decode_query(json!({
+            "type": "fare",
+            "station-from": "MRI",
+            "station-to": "BOO",
+        }))
+        .unwrap();
+        assert!(matches!(
+            query,
+            BatchQuery::Fare { station_from, station_to }
+                if station_from == "MRI" && station_to == "BOO"
+        ));
+    }
+
+    #[test]
+    fn decode_query_rejects_malformed_queries() {
+        for query in [
+            json!({"type": "weather"}),
+            json!({"type": "train-schedule"}),
+            json!({"type": "station-schedule", "station": "MRI", "time-from": "noon"}),
+            json!(42),
+        ] {
+            let err = decode_query(query).unwrap_err();
+            assert_eq!(err.status_code().as_u16(), 400);
+        }
+    }
+
+    #[actix_web::test]
+    async fn run_batch_fails_malformed_queries_in_their_own_slot() {
+        let store = Store::open(Path::new(":memory:")).unwrap();
+        let upstream = Upstream::new(1, store).unwrap();
+        let queries = vec![
+            json!({"type": "weather"}),
+            json!({"type": "fare", "station-from": "NOPE", "station-to": "MRI"}),
+            json!("distance"),
+        ];
+        let results = run_batch(&upstream, queries, 2).await;
+        assert_eq!(results.len(), 3);
+        for result in &results {
+            assert_eq!(result.status, 400);
+            assert!(result.body.is_none());
+        }
+        let parameter = |result: &BatchResult| {
+            serde_json::to_value(result.error.as_ref().unwrap()).unwrap()["parameter"].clone()
+        };
+        assert_eq!(parameter(&results[0]), Value::Null);
+        assert_eq!(parameter(&results[1]), json!("station-from"));
+    }
+}
@@ -0,0 +1,71 @@
+use std::{env, io, net::SocketAddr, path::PathBuf, str::FromStr};
+
+const DEFAULT_FETCH_CONCURRENCY: usize = 8;
+const DEFAULT_UPSTREAM_MAX_REQUESTS: usize = 32;
+const DEFAULT_DEPARTURE_POLL_SECONDS: u64 = 30;
+const DEFAULT_ALERT_LEAD_MINUTES: i64 = 30;
+const DEFAULT_DATABASE_FILE: &str = "krl-service.db";
+const DEFAULT_GRPC_ADDRESS: &str = "127.0.0.1:50051";
+
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    /// Maximum number of candidate paths, stations or trains a single
+    /// request fans out over at once.
+    pub fetch_concurrency: usize,
+    /// Maximum number of upstream requests in flight across the whole
+    /// service, however many searches are running.
+    pub upstream_max_requests: usize,
+    /// Seconds between upstream polls of a station with live subscribers.
+    pub departure_poll_seconds: u64,
+    /// Minutes before a commute's departure that its alert is first sent.
+    pub alert_lead_minutes: i64,
+    /// SQLite database harvested schedules, fares and distances are kept in.
+    pub database_file: PathBuf,
+    /// Public http(s) URL that detected timetable changes are POSTed to, if any.
+    pub timetable_webhook_url: Option<String>,
+    /// Address the gRPC server listens on, next to the HTTP server.
+    pub grpc_address: SocketAddr,
+}
+
+/// Reads a positive setting from the environment, falling back to `default`
+/// when it is unset or invalid.
+fn env_positive<T: FromStr + PartialOrd + Default>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .filter(|value| *value > T::default())
+        .unwrap_or(default)
+}
+
+impl AppConfig {
+    /// Reads the configuration. Fails only on settings that would leave part
+    /// of the service unreachable, such as an unparseable gRPC address.
+    pub fn from_env() -> io::Result<Self> {
+        let grpc_address = env::var("KRL_GRPC_ADDRESS")
+            .unwrap_or_else(|_| DEFAULT_GRPC_ADDRESS.into())
+            .parse()
+            .map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("KRL_GRPC_ADDRESS is not a socket address: {}", err),
+                )
+            })?;
+        Ok(Self {
+            fetch_concurrency: env_positive("KRL_FETCH_CONCURRENCY", DEFAULT_FETCH_CONCURRENCY),
+            upstream_max_requests: env_positive(
+                "KRL_UPSTREAM_MAX_REQUESTS",
+                DEFAULT_UPSTREAM_MAX_REQUESTS,
+            ),
+            departure_poll_seconds: env_positive(
+                "KRL_DEPARTURE_POLL_SECONDS",
+                DEFAULT_DEPARTURE_POLL_SECONDS,
+            ),
+            alert_lead_minutes: env_positive("KRL_ALERT_LEAD_MINUTES", DEFAULT_ALERT_LEAD_MINUTES),
+            database_file: env::var("KRL_DATABASE_FILE")
+                .unwrap_or_else(|_| DEFAULT_DATABASE_FILE.into())
+                .into(),
+            timetable_webhook_url: env::var("KRL_TIMETABLE_WEBHOOK_URL").ok(),
+            grpc_address,
+        })
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration as StdDuration,
+};
+
+use actix_web::{rt, web::Bytes};
+use chrono::{Duration, NaiveDateTime};
+use futures::{stream, Stream};
+use serde_derive::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    fetch::{fetch_station_schedule_with_warnings, Upstream},
+    model::{jakarta_now, DataWarning, ParsedRows, StationSchedule},
+    station::Station,
+};
+
+const DEPARTURE_WINDOW_MINUTES: i64 = 120;
+const EVENT_BUFFER: usize = 16;
+const KEEP_ALIVE_SECONDS: u64 = 15;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DepartureEvent {
+    Snapshot {
+        departures: Vec<StationSchedule>,
+        warnings: Vec<DataWarning>,
+    },
+    Diff {
+        departed: Vec<StationSchedule>,
+        added: Vec<StationSchedule>,
+        changed: Vec<StationSchedule>,
+        removed: Vec<StationSchedule>,
+        /// Warnings of the poll that produced this diff.
+        warnings: Vec<DataWarning>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl DepartureEvent {
+    fn name(&self) -> &str {
+        match self {
+            DepartureEvent::Snapshot { .. } => "snapshot",
+            DepartureEvent::Diff { .. } => "diff",
+            DepartureEvent::Error { .. } => "error",
+        }
+    }
+
+    fn to_sse(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data))
+    }
+}
+
+struct StationFeed {
+    sender: broadcast::Sender<DepartureEvent>,
+    latest: Option<ParsedRows<StationSchedule>>,
+}
+
+/// Shares one upstream poller per station between all of its subscribers.
+/// A poller stops on its own once its station has no subscribers left.
+pub struct DepartureHub {
+    feeds: Mutex<HashMap<Station, StationFeed>>,
+    poll_interval: StdDuration,
+    upstream: Arc<Upstream>,
+}
+
+impl DepartureHub {
+    pub fn new(poll_seconds: u64, upstream: Arc<Upstream>) -> Self {
+        Self {
+            feeds: Mutex::new(HashMap::new()),
+            poll_interval: StdDuration::from_secs(poll_seconds),
+            upstream,
+        }
+    }
+
+    fn subscribe(
+        self: &Arc<Self>,
+        station: Station,
+    ) -> (
+        Option<ParsedRows<StationSchedule>>,
+        broadcast::Receiver<DepartureEvent>,
+    ) {
+        let mut feeds = self.feeds.lock().unwrap();
+        if let Some(feed) = feeds.get(&station) {
+            return (feed.latest.clone(), feed.sender.subscribe());
+        }
+
+        let (sender, receiver) = broadcast::channel(EVENT_BUFFER);
+        feeds.insert(
+            station,
+            StationFeed {
+                sender,
+                latest: None,
+            },
+        );
+        let hub = self.clone();
+        rt::spawn(async move { hub.poll(station).await });
+        (None, receiver)
+    }
+
+    fn latest(&self, station: Station) -> Option<ParsedRows<StationSchedule>> {
+        let feeds = self.feeds.lock().unwrap();
+        feeds.get(&station).and_then(|feed| feed.latest.clone())
+    }
+
+    /// Publishes the event, or retires the feed when nobody is listening.
+    fn publish(
+        &self,
+        station: Station,
+        event: Option<DepartureEvent>,
+        latest: Option<ParsedRows<StationSchedule>>,
+    ) -> bool {
+        let mut feeds = self.feeds.lock().unwrap();
+        let Some(feed) = feeds.get_mut(&station) else {
+            return false;
+        };
+        if feed.sender.receiver_count() == 0 {
+            feeds.remove(&station);
+            return false;
+        }
+        if latest.is_some() {
+            feed.latest = latest;
+        }
+        if let Some(event) = event {
+            let _ = feed.sender.send(event);
+        }
+        true
+    }
+
+    async fn poll(self: Arc<Self>, station: Station) {
+        let mut previous: Option<ParsedRows<StationSchedule>> = None;
+        loop {
+            let now = jakarta_now();
+            let event = match fetch_upcoming_departures(&self.upstream, station, now).await {
+                Ok((departures, warnings)) => {
+                    let event = match &previous {
+                        None => Some(DepartureEvent::Snapshot {
+                            departures: departures.clone(),
+                            warnings: warnings.clone(),
+                        }),
+                        Some((previous, _)) => {
+                            diff_departures(previous, &departures, &warnings, now)
+                        }
+                    };
+                    previous = Some((departures, warnings));
+                    event
+                }
+                Err(err) => Some(DepartureEvent::Error {
+                    message: err.detail(),
+                }),
+            };
+            if !self.publish(station, event, previous.clone()) {
+                return;
+            }
+            rt::time::sleep(self.poll_interval).await;
+        }
+    }
+}
+
+async fn fetch_upcoming_departures(
+    upstream: &Upstream,
+    station: Station,
+    now: NaiveDateTime,
+) -> Result<ParsedRows<StationSchedule>, crate::error::AppError> {
+    let time_from = now.time();
+    let time_to = (now + Duration::minutes(DEPARTURE_WINDOW_MINUTES)).time();
+    let time_to = if time_to < time_from {
+        NaiveDateTime::MAX.time()
+    } else {
+        time_to
+    };
+    fetch_station_schedule_with_warnings(upstream, station, time_from, time_to).await
+}
+
+fn diff_departures(
+    previous: &[StationSchedule],
+    current: &[StationSchedule],
+    warnings: &[DataWarning],
+    now: NaiveDateTime,
+) -> Option<DepartureEvent> {
+    let find = |schedules: &[StationSchedule], train_id: &str| {
+        schedules
+            .iter()
+            .find(|schedule| schedule.train_id == train_id)
+            .cloned()
+    };
+
+    let mut departed = vec![];
+    let mut removed = vec![];
+    for schedule in previous {
+        if find(current, &schedule.train_id).is_none() {
+            if schedule.time_est <= now.time() {
+                departed.push(schedule.clone());
+            } else {
+                removed.push(schedule.clone());
+            }
+        }
+    }
+    let mut added = vec![];
+    let mut changed = vec![];
+    for schedule in current {
+        match find(previous, &schedule.train_id) {
+            None => added.push(schedule.clone()),
+            Some(before) if before != *schedule => changed.push(schedule.clone()),
+            Some(_) => {}
+        }
+    }
+
+    if departed.is_empty() && added.is_empty() && changed.is_empty() && removed.is_empty() {
+        None
+    } else {
+        Some(DepartureEvent::Diff {
+            departed,
+            added,
+            changed,
+            removed,
+            warnings: warnings.to_vec(),
+        })
+    }
+}
+
+enum StreamState {
+    Snapshot(ParsedRows<StationSchedule>),
+    Listening,
+}
+
+/// Server-Sent Events for a station: the current departures first, then a
+/// diff whenever a train leaves or the upstream schedule changes.
+pub fn departure_stream(
+    hub: Arc<DepartureHub>,
+    station: Station,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let (latest, receiver) = hub.subscribe(station);
+    let state = match latest {
+        Some(departures) => StreamState::Snapshot(departures),
+        None => StreamState::Listening,
+    };
+
+    stream::unfold(
+        (hub, receiver, state),
+        move |(hub, mut receiver, state)| async move {
+            if let StreamState::Snapshot((departures, warnings)) = state {
+                let event = DepartureEvent::Snapshot {
+                    departures,
+                    warnings,
+                };
+                return Some((Ok(event.to_sse()), (hub, receiver, StreamState::Listening)));
+            }
+            let keep_alive = StdDuration::from_secs(KEEP_ALIVE_SECONDS);
+            let bytes = match rt::time::timeout(keep_alive, receiver.recv()).await {
+                Err(_) => Bytes::from_static(b": keep-alive\n\n"),
+                Ok(Ok(event)) => event.to_sse(),
+                // Missed diffs cannot be replayed, so start over from the
+                // latest known departures.
+                Ok(Err(RecvError::Lagged(_))) => {
+                    let (departures, warnings) = hub.latest(station).unwrap_or_default();
+                    DepartureEvent::Snapshot {
+                        departures,
+                        warnings,
+                    }
+                    .to_sse()
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+            };
+            Some((Ok(bytes), (hub, receiver, StreamState::Listening)))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::test_util::time;
+
+    fn departure(train_id: &str, time_est: &str) -> StationSchedule {
+        StationSchedule {
+            train_id: train_id.into(),
+            route_name: "BOGOR-JAKARTAKOTA".into(),
+            time_est: time(time_est),
+        }
+    }
+
+    fn at(time_now: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_time(time(time_now))
+    }
+
+    fn train_ids(schedules: &[StationSchedule]) -> Vec<&str> {
+        schedules
+            .iter()
+            .map(|schedule| schedule.train_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_departures_send_nothing() {
+        let departures = [departure("1", "06:00"), departure("2", "06:10")];
+        assert!(diff_departures(&departures, &departures, &[], at("05:55")).is_none());
+    }
+
+    #[test]
+    fn diff_sorts_trains_by_what_happened_to_them() {
+        let previous = [
+            departure("1", "06:00"),
+            departure("2", "06:10"),
+            departure("3", "06:20"),
+        ];
+        let current = [
+            departure("2", "06:10"),
+            departure("3", "06:25"),
+            departure("4", "06:30"),
+        ];
+        let Some(DepartureEvent::Diff {
+            departed,
+            added,
+            changed,
+            removed,
+            ..
+        }) = diff_departures(&previous, &current, &[], at("06:05"))
+        else {
+            panic!("expected a diff");
+        };
+        assert_eq!(train_ids(&departed), vec!["1"]);
+        assert_eq!(train_ids(&added), vec!["4"]);
+        assert_eq!(train_ids(&changed), vec!["3"]);
+        assert_eq!(changed[0].time_est, time("06:25"));
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn trains_gone_before_their_time_are_removed() {
+        let previous = [departure("1", "06:00"), departure("2", "06:10")];
+        let current = [departure("1", "06:00")];
+        let Some(DepartureEvent::Diff {
+            departed, removed, ..
+        }) = diff_departures(&previous, &current, &[], at("06:05"))
+        else {
+            panic!("expected a diff");
+        };
+        assert!(departed.is_empty());
+        assert_eq!(train_ids(&removed), vec!["2"]);
+    }
+}
@@ -1,16 +1,61 @@
 use core::fmt;
+use std::{
+    future::{ready, Ready},
+    ops::Deref,
+};
 
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{
+    dev::Payload,
+    error::{JsonPayloadError, PathError},
+    http::header::ContentType,
+    FromRequest, HttpRequest, HttpResponse, ResponseError,
+};
 use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde_derive::Serialize;
+use serde_path_to_error::Segment;
 use strum::ParseError;
+use utoipa::{IntoResponses, ToSchema};
 
 #[derive(Debug)]
 pub enum AppErrorType {
     NotFoundError,
-    ReqwestError,
-    InvalidTimeFormat,
+    UpstreamUnavailableError,
+    UpstreamBadDataError,
     InvalidRequestParameter,
+    NoRouteError,
+    TimeoutError,
+    StorageError,
+    InternalError,
+}
+
+impl AppErrorType {
+    /// Machine readable code, stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppErrorType::NotFoundError => "not-found",
+            AppErrorType::UpstreamUnavailableError => "upstream-unavailable",
+            AppErrorType::UpstreamBadDataError => "upstream-bad-data",
+            AppErrorType::InvalidRequestParameter => "invalid-parameter",
+            AppErrorType::NoRouteError => "no-route",
+            AppErrorType::TimeoutError => "timeout",
+            AppErrorType::StorageError => "storage-failure",
+            AppErrorType::InternalError => "internal-error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            AppErrorType::NotFoundError => "Not found",
+            AppErrorType::UpstreamUnavailableError => "Upstream unavailable",
+            AppErrorType::UpstreamBadDataError => "Upstream returned bad data",
+            AppErrorType::InvalidRequestParameter => "Invalid parameter",
+            AppErrorType::NoRouteError => "No route",
+            AppErrorType::TimeoutError => "Upstream timeout",
+            AppErrorType::StorageError => "Storage failure",
+            AppErrorType::InternalError => "Internal error",
+        }
+    }
 }
 
 impl fmt::Display for AppErrorType {
@@ -24,6 +69,8 @@ pub struct AppError {
     pub message: Option<String>,
     pub cause: Option<String>,
     pub error_type: AppErrorType,
+    /// Request parameter that caused the error, when there is one.
+    pub parameter: Option<String>,
 }
 
 impl fmt::Display for AppError {
@@ -34,10 +81,21 @@ impl fmt::Display for AppError {
 
 impl From<reqwest::Error> for AppError {
     fn from(err: reqwest::Error) -> Self {
+        let (message, error_type) = if err.is_timeout() {
+            ("API request timed out", AppErrorType::TimeoutError)
+        } else if err.is_decode() {
+            (
+                "API returned an unexpected response",
+                AppErrorType::UpstreamBadDataError,
+            )
+        } else {
+            ("API request failed", AppErrorType::UpstreamUnavailableError)
+        };
         AppError {
             cause: Some(err.to_string()),
-            message: Some("API request failed".into()),
-            error_type: AppErrorType::ReqwestError,
+            message: Some(message.into()),
+            error_type,
+            parameter: None,
         }
     }
 }
@@ -48,46 +106,274 @@ impl From<ParseError> for AppError {
             cause: Some(value.to_string()),
             message: Some("The requested item was not found".into()),
             error_type: AppErrorType::NotFoundError,
+            parameter: None,
         }
     }
 }
 
 impl AppError {
-    fn message(&self) -> String {
-        match &*self {
+    pub fn detail(&self) -> String {
+        match &self.cause {
+            Some(cause) => cause.clone(),
+            None => self.message(),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
             AppError {
-                cause: _,
                 message: Some(message),
-                error_type: _,
+                ..
             } => message.clone(),
             AppError {
-                cause: _,
                 message: None,
                 error_type: AppErrorType::NotFoundError,
+                ..
             } => "The requested item was not found".to_string(),
             _ => "An unexpected error has occurred".to_string(),
         }
     }
+
+    fn invalid_parameter(message: String, parameter: Option<String>) -> Self {
+        AppError {
+            message: Some(message),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter,
+        }
+    }
+}
+
+/// Parses an id given in request parameter `parameter`, reporting an unknown
+/// id as an invalid parameter rather than a missing resource.
+pub fn parse_param<T: std::str::FromStr<Err = ParseError>>(
+    value: &str,
+    parameter: &str,
+) -> Result<T, AppError> {
+    T::from_str(value).map_err(|_| {
+        AppError::invalid_parameter(
+            format!("Unknown value '{}' for {}", value, parameter),
+            Some(parameter.into()),
+        )
+    })
+}
+
+/// Body of every error response, an RFC 7807 problem.
+#[derive(Serialize, ToSchema)]
+pub struct AppErrorResponse {
+    /// Identifies the problem type, always `urn:krl-service:problem:<code>`.
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: String,
+    status: u16,
+    detail: String,
+    /// Stable machine readable error code.
+    #[schema(example = "invalid-parameter")]
+    code: String,
+    /// Request parameter that caused the error, when there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameter: Option<String>,
+}
+
+/// The 500 response of every endpoint backed by local storage, shared so
+/// they all document it the same way. Only ever named in `responses(...)`.
+#[allow(dead_code)]
+#[derive(IntoResponses)]
+#[response(
+    status = 500,
+    description = "Storage failure",
+    content_type = "application/problem+json"
+)]
+pub struct StorageFailureResponse(AppErrorResponse);
+
+/// What the client is told went wrong. The cause of a rejected parameter
+/// says why it was rejected; other causes are internal, such as upstream
+/// error text, and are only logged.
+fn problem_detail(err: &AppError) -> String {
+    match (&err.error_type, &err.cause) {
+        (AppErrorType::InvalidRequestParameter, Some(cause)) => {
+            format!("{}: {}", err.message(), cause)
+        }
+        _ => err.message(),
+    }
 }
 
-#[derive(Serialize)]
-struct AppErrorResponse {
-    error: String,
+impl From<&AppError> for AppErrorResponse {
+    fn from(err: &AppError) -> Self {
+        let code = err.error_type.code();
+        AppErrorResponse {
+            problem_type: format!("urn:krl-service:problem:{}", code),
+            title: err.error_type.title().into(),
+            status: err.status_code().as_u16(),
+            detail: problem_detail(err),
+            code: code.into(),
+            parameter: err.parameter.clone(),
+        }
+    }
 }
 
 impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self.error_type {
             AppErrorType::NotFoundError => StatusCode::NOT_FOUND,
-            AppErrorType::ReqwestError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppErrorType::InvalidTimeFormat => StatusCode::INTERNAL_SERVER_ERROR,
+            AppErrorType::UpstreamUnavailableError => StatusCode::BAD_GATEWAY,
+            AppErrorType::UpstreamBadDataError => StatusCode::BAD_GATEWAY,
             AppErrorType::InvalidRequestParameter => StatusCode::BAD_REQUEST,
+            AppErrorType::NoRouteError => StatusCode::NOT_FOUND,
+            AppErrorType::TimeoutError => StatusCode::GATEWAY_TIMEOUT,
+            AppErrorType::StorageError => StatusCode::INTERNAL_SERVER_ERROR,
+            AppErrorType::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(AppErrorResponse {
-            error: self.message(),
-        })
+        let body = serde_json::to_string(&AppErrorResponse::from(self)).unwrap_or_default();
+        HttpResponse::build(self.status_code())
+            .content_type(ContentType("application/problem+json".parse().unwrap()))
+            .body(body)
+    }
+}
+
+/// Picks the field name out of serde messages such as "missing field `station`".
+fn offending_field(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("field `")?;
+    let (field, _) = rest.split_once('`')?;
+    Some(field.into())
+}
+
+/// Deserializes a query string, naming the parameter whose value was
+/// missing or failed to parse.
+fn parse_query<T: DeserializeOwned>(query: &str) -> Result<T, AppError> {
+    let deserializer =
+        serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+    serde_path_to_error::deserialize(deserializer).map_err(|err| {
+        let reason = err.inner().to_string();
+        match err.path().iter().next() {
+            Some(Segment::Map { key }) => AppError::invalid_parameter(
+                format!("Invalid value for {}: {}", key, reason),
+                Some(key.clone()),
+            ),
+            _ => {
+                let parameter = offending_field(&reason);
+                AppError::invalid_parameter(reason, parameter)
+            }
+        }
+    })
+}
+
+/// Query string extractor like `web::Query`, except that malformed query
+/// strings are reported as problems naming the offending parameter.
+pub struct Query<T>(pub T);
+
+impl<T> Query<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Query<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    type Error = AppError;
+    type Future = Ready<Result<Self, AppError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(parse_query(req.query_string()).map(Query))
+    }
+}
+
+/// Reports malformed request bodies as problems instead of plain text.
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let message = err.to_string();
+    let parameter = offending_field(&message);
+    AppError::invalid_parameter(message, parameter).into()
+}
+
+/// Reports malformed path segments as problems instead of plain text.
+pub fn path_error_handler(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    AppError::invalid_parameter(err.to_string(), None).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+    use serde_derive::Deserialize;
+
+    use super::*;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "kebab-case")]
+    struct Param {
+        time_from: NaiveTime,
+        k: Option<usize>,
+        direction: Option<Direction>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "kebab-case")]
+    enum Direction {
+        Up,
+        Down,
+    }
+
+    fn rejected_parameter(query: &str) -> Option<String> {
+        parse_query::<Param>(query).unwrap_err().parameter
+    }
+
+    #[test]
+    fn query_errors_name_a_missing_parameter() {
+        assert_eq!(rejected_parameter("k=1"), Some("time-from".into()));
+    }
+
+    #[test]
+    fn query_errors_name_an_invalid_value() {
+        assert_eq!(
+            rejected_parameter("time-from=25:00"),
+            Some("time-from".into())
+        );
+    }
+
+    #[test]
+    fn query_errors_name_a_value_of_the_wrong_type() {
+        assert_eq!(rejected_parameter("time-from=10:00&k=x"), Some("k".into()));
+    }
+
+    #[test]
+    fn query_errors_name_an_unknown_variant() {
+        assert_eq!(
+            rejected_parameter("time-from=10:00&direction=left"),
+            Some("direction".into())
+        );
+    }
+
+    #[test]
+    fn problem_detail_gives_the_reason_only_for_rejected_parameters() {
+        let invalid = AppError {
+            message: Some("Webhook url must be a public http or https url".into()),
+            cause: Some("Unsupported scheme ftp".into()),
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("webhook_url".into()),
+        };
+        assert_eq!(
+            AppErrorResponse::from(&invalid).detail,
+            "Webhook url must be a public http or https url: Unsupported scheme ftp"
+        );
+        let upstream = AppError {
+            message: Some("API request failed".into()),
+            cause: Some("connection refused".into()),
+            error_type: AppErrorType::UpstreamUnavailableError,
+            parameter: None,
+        };
+        assert_eq!(
+            AppErrorResponse::from(&upstream).detail,
+            "API request failed"
+        );
     }
 }
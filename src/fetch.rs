@@ -1,29 +1,112 @@
+use std::time::Duration;
+
 use chrono::NaiveTime;
 use serde_derive::Deserialize;
+use serde_json::Value;
+use tokio::sync::Semaphore;
 
 use crate::error::AppError;
 use crate::error::AppErrorType;
+use crate::model::jakarta_now;
+use crate::model::DataWarning;
 use crate::model::Distance;
 use crate::model::Fare;
+use crate::model::ParsedRows;
 use crate::model::RouteInfoDTO;
 use crate::model::StationSchedule;
-use crate::model::StationScheduleDTO;
 use crate::model::TrainSchedule;
-use crate::model::TrainScheduleDTO;
+use crate::quality;
 use crate::station::Station;
+use crate::store::Store;
+
+/// How long to wait for KRL before giving up on a request.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(20);
 
 #[derive(Deserialize, Debug)]
 pub struct APIResponse<T> {
     data: Vec<T>,
 }
 
-pub async fn fetch_station_schedule(
+/// Shared access to KRL. Every upstream request in the process goes through
+/// one of these, so `max_requests` bounds the requests in flight across all
+/// route searches, streams and background jobs together. Answers are
+/// harvested into `store`, which also stands in when KRL fails.
+pub struct Upstream {
+    client: reqwest::Client,
+    permits: Semaphore,
+    store: Store,
+}
+
+impl Upstream {
+    pub fn new(max_requests: usize, store: Store) -> Result<Self, AppError> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(UPSTREAM_TIMEOUT)
+                .build()?,
+            permits: Semaphore::new(max_requests),
+            store,
+        })
+    }
+
+    /// Rows of a KRL response, holding a permit until the body is read.
+    async fn get_rows(&self, url: String, what: &str) -> Result<Vec<Value>, AppError> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("upstream semaphore is never closed");
+        let res = self.client.get(url).send().await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(res.json::<APIResponse<Value>>().await?.data),
+            status => Err(AppError {
+                message: Some(format!("Failed to fetch {}", what)),
+                cause: Some(format!("KRL responded with {}", status)),
+                error_type: AppErrorType::UpstreamUnavailableError,
+                parameter: None,
+            }),
+        }
+    }
+}
+
+/// Rows of an upstream response that could be used, with warnings for the
+/// rest. Fails only when upstream sent rows and none of them were usable, so
+/// a completely broken response still falls back to the store.
+fn usable_rows<T>(
+    source: &str,
+    rows: Vec<Value>,
+    from_rows: fn(Vec<Value>) -> ParsedRows<T>,
+) -> Result<ParsedRows<T>, AppError> {
+    let count = rows.len();
+    let (parsed, warnings) = from_rows(rows);
+    quality::record(source, &warnings);
+    if parsed.is_empty() && count > 0 {
+        return Err(AppError {
+            message: Some(format!("Failed to parse {}", source)),
+            cause: Some(format!(
+                "None of the {} rows KRL returned were valid",
+                count
+            )),
+            error_type: AppErrorType::UpstreamBadDataError,
+            parameter: None,
+        });
+    }
+    Ok((parsed, warnings))
+}
+
+/// Logs a failed write to the local store. Harvesting is best effort and
+/// must never fail a request that upstream already answered.
+fn log_store_error(result: Result<(), AppError>) {
+    if let Err(err) = result {
+        log::warn!("Failed to store upstream data: {}", err.detail());
+    }
+}
+
+pub async fn fetch_upstream_station_schedule(
+    upstream: &Upstream,
     station: Station,
     time_from: NaiveTime,
     time_to: NaiveTime,
-) -> Result<Vec<StationSchedule>, AppError> {
-    let client = reqwest::Client::builder().build()?;
-
+) -> Result<ParsedRows<StationSchedule>, AppError> {
     let url = format!(
         "https://api-partner.krl.co.id/krlweb/v1/schedule?stationid={}&timefrom={}&timeto={}",
         station.id(),
@@ -31,99 +114,214 @@ pub async fn fetch_station_schedule(
         time_to.format("%H:%M")
     );
 
-    let res = client.get(url).send().await?;
+    let rows = upstream.get_rows(url, "station schedule").await?;
+    usable_rows(
+        &format!("station schedule of {}", station.id()),
+        rows,
+        StationSchedule::from_rows,
+    )
+}
 
-    match res.status() {
-        reqwest::StatusCode::OK => {
-            let dtos = res.json::<APIResponse<StationScheduleDTO>>().await?.data;
-            let schedules_result: Result<Vec<StationSchedule>, AppError> = dtos
-                .into_iter()
-                .map(|dto| StationSchedule::from_dto(dto))
-                .collect();
-            Ok(schedules_result?)
+/// Fetches a station schedule from KRL, storing it for today. When KRL
+/// fails, the most recently stored schedule is served instead, with a
+/// warning saying so. Rows KRL got wrong are reported as warnings.
+pub async fn fetch_station_schedule_with_warnings(
+    upstream: &Upstream,
+    station: Station,
+    time_from: NaiveTime,
+    time_to: NaiveTime,
+) -> Result<ParsedRows<StationSchedule>, AppError> {
+    let today = jakarta_now().date();
+    match fetch_upstream_station_schedule(upstream, station, time_from, time_to).await {
+        Ok((schedules, warnings)) => {
+            let stored = schedules.clone();
+            log_store_error(
+                upstream
+                    .store
+                    .run(move |store| store.save_station_schedule(today, station, &stored))
+                    .await,
+            );
+            Ok((schedules, warnings))
+        }
+        Err(err) => {
+            let stored = upstream
+                .store
+                .run(move |store| store.latest_station_schedule(station, today, time_from, time_to))
+                .await;
+            match stored {
+                Ok(Some((date, schedules))) => {
+                    Ok((schedules, vec![DataWarning::served_from_store(date)]))
+                }
+                _ => Err(err),
+            }
         }
-        _ => Err(AppError {
-            message: Some("Failed to fetch station schedule".into()),
-            cause: None,
-            error_type: AppErrorType::NotFoundError,
-        }),
     }
 }
 
-pub async fn fetch_train_schedule(train_id: &str) -> Result<Vec<TrainSchedule>, AppError> {
-    let client = reqwest::Client::builder().build()?;
+pub async fn fetch_station_schedule(
+    upstream: &Upstream,
+    station: Station,
+    time_from: NaiveTime,
+    time_to: NaiveTime,
+) -> Result<Vec<StationSchedule>, AppError> {
+    Ok(
+        fetch_station_schedule_with_warnings(upstream, station, time_from, time_to)
+            .await?
+            .0,
+    )
+}
 
+pub async fn fetch_upstream_train_schedule(
+    upstream: &Upstream,
+    train_id: &str,
+) -> Result<ParsedRows<TrainSchedule>, AppError> {
     let url = format!(
         "https://api-partner.krl.co.id/krlweb/v1/schedule-train?trainid={}",
         train_id
     );
 
-    let res = client.get(url).send().await?;
-    match res.status() {
-        reqwest::StatusCode::OK => {
-            let dtos = res.json::<APIResponse<TrainScheduleDTO>>().await?.data;
-            let schedules_result: Result<Vec<TrainSchedule>, AppError> = dtos
-                .into_iter()
-                .map(|dto| TrainSchedule::from_dto(dto))
-                .collect();
-            Ok(schedules_result?)
-        }
+    let rows = upstream.get_rows(url, "train schedule").await?;
+    usable_rows(
+        &format!("train schedule of {}", train_id),
+        rows,
+        TrainSchedule::from_rows,
+    )
+}
 
-        _ => Err(AppError {
-            message: Some("Failed to fetch train schedule".into()),
-            cause: None,
-            error_type: AppErrorType::ReqwestError,
-        }),
+/// Fetches a train schedule from KRL, storing it for today. When KRL fails,
+/// the most recently stored schedule of the train is served instead, with a
+/// warning saying so. Rows KRL got wrong are reported as warnings.
+pub async fn fetch_train_schedule_with_warnings(
+    upstream: &Upstream,
+    train_id: &str,
+) -> Result<ParsedRows<TrainSchedule>, AppError> {
+    let today = jakarta_now().date();
+    match fetch_upstream_train_schedule(upstream, train_id).await {
+        Ok((schedules, warnings)) => {
+            let (train_id, stored) = (train_id.to_string(), schedules.clone());
+            log_store_error(
+                upstream
+                    .store
+                    .run(move |store| store.save_train_schedule(today, &train_id, &stored))
+                    .await,
+            );
+            Ok((schedules, warnings))
+        }
+        Err(err) => {
+            let train_id = train_id.to_string();
+            let stored = upstream
+                .store
+                .run(move |store| store.latest_train_schedule(&train_id, today))
+                .await;
+            match stored {
+                Ok(Some((date, schedules))) if !schedules.is_empty() => {
+                    Ok((schedules, vec![DataWarning::served_from_store(date)]))
+                }
+                _ => Err(err),
+            }
+        }
     }
 }
 
-pub async fn fetch_fare(station_from: Station, station_to: Station) -> Result<Fare, AppError> {
-    let client = reqwest::Client::builder().build()?;
+pub async fn fetch_train_schedule(
+    upstream: &Upstream,
+    train_id: &str,
+) -> Result<Vec<TrainSchedule>, AppError> {
+    Ok(fetch_train_schedule_with_warnings(upstream, train_id)
+        .await?
+        .0)
+}
 
+async fn fetch_upstream_route_info(
+    upstream: &Upstream,
+    station_from: Station,
+    station_to: Station,
+) -> Result<(RouteInfoDTO, Vec<DataWarning>), AppError> {
     let url = format!(
         "https://api-partner.krl.co.id/krlweb/v1/fare?stationfrom={}&stationto={}",
         station_from.id(),
         station_to.id()
     );
 
-    let res = client.get(url).send().await?;
+    let rows = upstream.get_rows(url, "route info").await?;
+    let (route_info, warnings) = usable_rows(
+        &format!(
+            "route info from {} to {}",
+            station_from.id(),
+            station_to.id()
+        ),
+        rows,
+        RouteInfoDTO::from_rows,
+    )?;
+    let route_info = route_info.into_iter().next().ok_or_else(|| AppError {
+        message: Some("Failed to fetch route info".into()),
+        cause: Some(format!(
+            "KRL returned no route info from {} to {}",
+            station_from.id(),
+            station_to.id()
+        )),
+        error_type: AppErrorType::UpstreamBadDataError,
+        parameter: None,
+    })?;
+    Ok((route_info, warnings))
+}
 
-    match res.status() {
-        reqwest::StatusCode::OK => {
-            let route_info = res.json::<APIResponse<RouteInfoDTO>>().await?.data;
-            Ok(route_info.into_iter().next().unwrap().into())
+/// Fare and distance share one upstream endpoint, so both go through here.
+/// Falls back to the store like the schedule fetches do.
+async fn fetch_route_info(
+    upstream: &Upstream,
+    station_from: Station,
+    station_to: Station,
+) -> Result<(RouteInfoDTO, Vec<DataWarning>), AppError> {
+    let today = jakarta_now().date();
+    match fetch_upstream_route_info(upstream, station_from, station_to).await {
+        Ok((route_info, warnings)) => {
+            let stored = route_info.clone();
+            log_store_error(
+                upstream
+                    .store
+                    .run(move |store| {
+                        store.save_route_info(today, station_from, station_to, &stored)
+                    })
+                    .await,
+            );
+            Ok((route_info, warnings))
+        }
+        Err(err) => {
+            let stored = upstream
+                .store
+                .run(move |store| store.route_info(station_from, station_to, today))
+                .await;
+            match stored {
+                Ok(Some((date, route_info))) => {
+                    Ok((route_info, vec![DataWarning::served_from_store(date)]))
+                }
+                _ => Err(err),
+            }
         }
-        _ => Err(AppError {
-            message: Some("Failed to fetch fare".into()),
-            cause: None,
-            error_type: AppErrorType::NotFoundError,
-        }),
     }
 }
 
+pub async fn fetch_fare(
+    upstream: &Upstream,
+    station_from: Station,
+    station_to: Station,
+) -> Result<Fare, AppError> {
+    let (route_info, warnings) = fetch_route_info(upstream, station_from, station_to).await?;
+    Ok(Fare {
+        warnings,
+        ..route_info.into()
+    })
+}
+
 pub async fn fetch_distance(
+    upstream: &Upstream,
     station_from: Station,
     station_to: Station,
 ) -> Result<Distance, AppError> {
-    let client = reqwest::Client::builder().build()?;
-
-    let url = format!(
-        "https://api-partner.krl.co.id/krlweb/v1/fare?stationfrom={}&stationto={}",
-        station_from.id(),
-        station_to.id()
-    );
-
-    let res = client.get(url).send().await?;
-
-    match res.status() {
-        reqwest::StatusCode::OK => {
-            let route_info = res.json::<APIResponse<RouteInfoDTO>>().await?.data;
-            Ok(route_info.into_iter().next().unwrap().into())
-        }
-        _ => Err(AppError {
-            message: Some("Failed to fetch distance".into()),
-            cause: None,
-            error_type: AppErrorType::NotFoundError,
-        }),
-    }
+    let (route_info, warnings) = fetch_route_info(upstream, station_from, station_to).await?;
+    Ok(Distance {
+        warnings,
+        ..route_info.into()
+    })
 }
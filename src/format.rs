@@ -0,0 +1,228 @@
+use std::borrow::Cow;
+
+use actix_web::{http::header, web::Bytes, HttpRequest, HttpResponse};
+use futures::stream;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+use crate::model::{StationSchedule, TrainSchedule};
+
+/// Response formats the tabular endpoints can produce.
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl OutputFormat {
+    /// An explicit `format=` wins over the `Accept` header, which wins over
+    /// the JSON default. Of the accepted formats the one with the highest `q`
+    /// is chosen, the first listed on a tie, and `q=0` rules a format out.
+    pub fn negotiate(format: Option<OutputFormat>, req: &HttpRequest) -> Self {
+        if let Some(format) = format {
+            return format;
+        }
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+        let mut best: Option<(OutputFormat, f32)> = None;
+        for media_range in accept.split(',') {
+            let mut params = media_range.split(';');
+            let format = match params.next().unwrap_or_default().trim() {
+                "application/json" => OutputFormat::Json,
+                "text/csv" => OutputFormat::Csv,
+                "application/x-ndjson" | "application/ndjson" => OutputFormat::Ndjson,
+                _ => continue,
+            };
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.);
+            if quality > 0. && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((format, quality));
+            }
+        }
+        best.map_or(OutputFormat::Json, |(format, _)| format)
+    }
+}
+
+/// A row of CSV output. Nested values are flattened into plain columns.
+pub trait CsvRecord {
+    fn header() -> &'static [&'static str];
+    fn record(&self) -> Vec<String>;
+}
+
+impl CsvRecord for StationSchedule {
+    fn header() -> &'static [&'static str] {
+        &["train_id", "route_name", "time_est"]
+    }
+
+    fn record(&self) -> Vec<String> {
+        vec![
+            self.train_id.clone(),
+            self.route_name.clone(),
+            self.time_est.format("%H:%M:%S").to_string(),
+        ]
+    }
+}
+
+impl CsvRecord for TrainSchedule {
+    fn header() -> &'static [&'static str] {
+        &["train_id", "station_id", "station_name", "time_est"]
+    }
+
+    fn record(&self) -> Vec<String> {
+        vec![
+            self.train_id.clone(),
+            self.station_id().to_string(),
+            self.station.name().to_string(),
+            self.time_est.format("%H:%M:%S").to_string(),
+        ]
+    }
+}
+
+/// One entry of a name to id listing such as `/station-list`.
+#[derive(Serialize)]
+pub struct NamedId {
+    pub name: String,
+    pub id: String,
+}
+
+impl NamedId {
+    pub fn from_map(map: &Map<String, Value>) -> Vec<Self> {
+        map.iter()
+            .map(|(name, id)| Self {
+                name: name.clone(),
+                id: id.as_str().unwrap_or_default().to_string(),
+            })
+            .collect()
+    }
+}
+
+impl CsvRecord for NamedId {
+    fn header() -> &'static [&'static str] {
+        &["name", "id"]
+    }
+
+    fn record(&self) -> Vec<String> {
+        vec![self.name.clone(), self.id.clone()]
+    }
+}
+
+/// Quotes a field when it contains a delimiter, quote or line break, and
+/// defuses one a spreadsheet would read as a formula, including one hidden
+/// behind a leading tab or carriage return, by prefixing `'`.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        Cow::Owned(format!("'{}", field))
+    } else {
+        Cow::Borrowed(field)
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        field
+    }
+}
+
+fn csv_line(fields: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    let mut line = fields
+        .into_iter()
+        .map(|field| csv_field(field.as_ref()).into_owned())
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+/// Renders the response in the negotiated format. `json` is the regular JSON
+/// body, which may be shaped differently from the rows and carry metadata
+/// such as warnings; CSV and NDJSON hold the rows alone, taken out of it by
+/// `rows`. NDJSON rows are serialized one by one as the body is sent.
+pub fn tabular_response<T, J>(
+    format: OutputFormat,
+    json: J,
+    rows: impl FnOnce(J) -> Vec<T>,
+) -> HttpResponse
+where
+    T: serde::Serialize + CsvRecord + 'static,
+    J: serde::Serialize,
+{
+    match format {
+        OutputFormat::Json => HttpResponse::Ok().json(json),
+        OutputFormat::Csv => {
+            let mut body = csv_line(T::header());
+            for row in rows(json) {
+                body.push_str(&csv_line(row.record()));
+            }
+            HttpResponse::Ok()
+                .content_type("text/csv; charset=utf-8")
+                .body(body)
+        }
+        OutputFormat::Ndjson => {
+            let lines = rows(json)
+                .into_iter()
+                .filter_map(|row| serde_json::to_vec(&row).ok())
+                .map(|mut line| {
+                    line.push(b'\n');
+                    Ok::<_, actix_web::Error>(Bytes::from(line))
+                });
+            HttpResponse::Ok()
+                .content_type("application/x-ndjson")
+                .streaming(stream::iter(lines))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn csv_field_defuses_formulas() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\t=1+1"), "'\t=1+1");
+        assert_eq!(csv_field("\r=1+1"), "\"'\r=1+1\"");
+        assert_eq!(csv_field("BOGOR"), "BOGOR");
+    }
+
+    fn negotiate(accept: &str) -> OutputFormat {
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT, accept))
+            .to_http_request();
+        OutputFormat::negotiate(None, &req)
+    }
+
+    #[test]
+    fn accept_header_is_weighed_by_quality() {
+        assert_eq!(negotiate("text/csv"), OutputFormat::Csv);
+        assert_eq!(negotiate("text/csv;q=0, */*"), OutputFormat::Json);
+        assert_eq!(
+            negotiate("text/csv;q=0.5, application/x-ndjson"),
+            OutputFormat::Ndjson
+        );
+        assert_eq!(negotiate("application/json, text/csv"), OutputFormat::Json);
+        assert_eq!(negotiate("text/html"), OutputFormat::Json);
+    }
+
+    #[test]
+    fn explicit_format_wins_over_accept() {
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT, "text/csv"))
+            .to_http_request();
+        assert_eq!(
+            OutputFormat::negotiate(Some(OutputFormat::Ndjson), &req),
+            OutputFormat::Ndjson
+        );
+    }
+}
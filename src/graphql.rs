@@ -0,0 +1,649 @@
+use std::{collections::HashMap, convert::Infallible, str::FromStr, sync::Arc};
+
+use actix_web::{get, post, rt, web, HttpResponse};
+use async_graphql::{
+    dataloader::{DataLoader, HashMapCache, Loader},
+    http::GraphiQLSource,
+    Context, EmptyMutation, EmptySubscription, ErrorExtensions, Object, Schema,
+};
+use chrono::NaiveTime;
+use futures::{stream, StreamExt};
+use strum::{IntoEnumIterator, ParseError};
+
+use crate::{
+    config::AppConfig,
+    error::{parse_param, AppError, AppErrorType},
+    fetch::{fetch_station_schedule_with_warnings, fetch_train_schedule_with_warnings, Upstream},
+    line::TrainLine,
+    model::{
+        DataWarning, Journey, Leg, LegMode, ParsedRows, StationSchedule, StopTime, TrainSchedule,
+    },
+    pathfinder::{choose_fastest_path, RouteConstraint},
+    route::parse_transit_duration,
+    station::Station,
+};
+
+pub type KrlSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// Deepest nesting a query may have. Station, line and train nodes link to
+/// each other, so without a bound one query could fan out over the network.
+const MAX_QUERY_DEPTH: usize = 10;
+/// Weight of a field that makes an upstream request; plain fields weigh 1.
+const FETCH_COMPLEXITY: usize = 100;
+/// Weight of a journey search, which fetches the trains of every candidate
+/// path rather than a single schedule.
+const JOURNEY_COMPLEXITY: usize = 20 * FETCH_COMPLEXITY;
+/// Total weight a query may have, about fifty upstream requests. Lists
+/// count their items once per element they may hold, so fetching fields
+/// nested under lists add up quickly.
+const MAX_QUERY_COMPLEXITY: usize = 50 * FETCH_COMPLEXITY;
+const DEFAULT_DEPARTURE_LIMIT: usize = 10;
+const MAX_DEPARTURE_LIMIT: usize = 50;
+
+/// Weight of a list of at most `len` items that weigh `child_complexity`
+/// each.
+fn list_complexity(len: usize, child_complexity: usize) -> usize {
+    len.saturating_mul(child_complexity)
+}
+
+fn station_count() -> usize {
+    Station::iter().len()
+}
+
+fn line_count() -> usize {
+    TrainLine::iter().len()
+}
+
+fn graphql_error(err: &AppError) -> async_graphql::Error {
+    async_graphql::Error::new(err.message()).extend_with(|_, extensions| {
+        extensions.set("code", err.error_type.code());
+        if let Some(parameter) = &err.parameter {
+            extensions.set("parameter", parameter.as_str());
+        }
+    })
+}
+
+fn parse_station(id: &str) -> async_graphql::Result<Station> {
+    Station::from_str(id).map_err(|err| graphql_error(&err.into()))
+}
+
+fn parse_line(id: &str) -> async_graphql::Result<TrainLine> {
+    TrainLine::from_str(id).map_err(|err| graphql_error(&err.into()))
+}
+
+fn parse_all<T: FromStr<Err = ParseError>>(
+    ids: Option<Vec<String>>,
+    argument: &str,
+) -> async_graphql::Result<Vec<T>> {
+    ids.unwrap_or_default()
+        .iter()
+        .map(|id| parse_param(id, argument).map_err(|err| graphql_error(&err)))
+        .collect()
+}
+
+/// Loads train schedules for every train id requested while resolving one
+/// level of a query. Upstream has no batch endpoint, so a batch is fetched
+/// with bounded concurrency; duplicates are fetched once per request.
+pub struct TrainScheduleLoader {
+    upstream: Arc<Upstream>,
+    concurrency: usize,
+}
+
+impl Loader<String> for TrainScheduleLoader {
+    type Value = Result<ParsedRows<TrainSchedule>, Arc<AppError>>;
+    type Error = Infallible;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, Self::Value>, Self::Error> {
+        let upstream = &self.upstream;
+        let schedules = stream::iter(keys.to_vec())
+            .map(|train_id| async move {
+                let schedules = fetch_train_schedule_with_warnings(upstream, &train_id)
+                    .await
+                    .map_err(Arc::new);
+                (train_id, schedules)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<HashMap<_, _>>()
+            .await;
+        Ok(schedules)
+    }
+}
+
+/// Loads station schedules for every station and time window requested
+/// while resolving one level of a query, the same way as
+/// [`TrainScheduleLoader`].
+pub struct StationScheduleLoader {
+    upstream: Arc<Upstream>,
+    concurrency: usize,
+}
+
+type StationScheduleKey = (Station, NaiveTime, NaiveTime);
+
+impl Loader<StationScheduleKey> for StationScheduleLoader {
+    type Value = Result<ParsedRows<StationSchedule>, Arc<AppError>>;
+    type Error = Infallible;
+
+    async fn load(
+        &self,
+        keys: &[StationScheduleKey],
+    ) -> Result<HashMap<StationScheduleKey, Self::Value>, Self::Error> {
+        let upstream = &self.upstream;
+        let schedules = stream::iter(keys.to_vec())
+            .map(|key| async move {
+                let (station, time_from, time_to) = key;
+                let schedules =
+                    fetch_station_schedule_with_warnings(upstream, station, time_from, time_to)
+                        .await
+                        .map_err(Arc::new);
+                (key, schedules)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<HashMap<_, _>>()
+            .await;
+        Ok(schedules)
+    }
+}
+
+async fn load_train(ctx: &Context<'_>, train_id: &str) -> async_graphql::Result<TrainNode> {
+    let loader = ctx.data_unchecked::<DataLoader<TrainScheduleLoader, HashMapCache>>();
+    match loader.load_one(train_id.to_string()).await {
+        Ok(Some(Ok((stops, warnings)))) => Ok(TrainNode {
+            id: train_id.to_string(),
+            stops,
+            warnings,
+        }),
+        Ok(Some(Err(err))) => Err(graphql_error(&err)),
+        _ => Err(async_graphql::Error::new(
+            "The requested item was not found",
+        )),
+    }
+}
+
+pub struct StationNode(Station);
+
+#[Object(name = "Station")]
+impl StationNode {
+    async fn id(&self) -> &str {
+        self.0.id()
+    }
+
+    async fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    async fn transit_station(&self) -> bool {
+        self.0.is_transit_station()
+    }
+
+    #[graphql(complexity = "list_complexity(line_count(), child_complexity)")]
+    async fn lines(&self) -> Vec<LineNode> {
+        self.0.line().into_iter().map(LineNode).collect()
+    }
+
+    /// Departures between `timeFrom` and `timeTo`, at most `limit` of them.
+    /// `limit` defaults to 10 and may be up to 50.
+    #[graphql(
+        complexity = "FETCH_COMPLEXITY + list_complexity(limit.min(MAX_DEPARTURE_LIMIT), \
+                            child_complexity)"
+    )]
+    async fn departures(
+        &self,
+        ctx: &Context<'_>,
+        time_from: Option<NaiveTime>,
+        time_to: Option<NaiveTime>,
+        #[graphql(default_with = "DEFAULT_DEPARTURE_LIMIT")] limit: usize,
+    ) -> async_graphql::Result<DepartureListNode> {
+        if limit > MAX_DEPARTURE_LIMIT {
+            return Err(graphql_error(&AppError {
+                message: Some(format!("limit cannot be more than {}", MAX_DEPARTURE_LIMIT)),
+                cause: None,
+                error_type: AppErrorType::InvalidRequestParameter,
+                parameter: Some("limit".into()),
+            }));
+        }
+        let time_from = time_from.unwrap_or(NaiveTime::MIN);
+        let time_to = time_to.unwrap_or_else(|| NaiveTime::from_hms_opt(23, 59, 59).unwrap());
+        let loader = ctx.data_unchecked::<DataLoader<StationScheduleLoader, HashMapCache>>();
+        let (mut departures, warnings) = match loader.load_one((self.0, time_from, time_to)).await {
+            Ok(Some(Ok(schedules))) => schedules,
+            Ok(Some(Err(err))) => return Err(graphql_error(&err)),
+            _ => {
+                return Err(async_graphql::Error::new(
+                    "The requested item was not found",
+                ))
+            }
+        };
+        departures.truncate(limit);
+        Ok(DepartureListNode {
+            items: departures
+                .into_iter()
+                .map(|departure| DepartureNode {
+                    station: self.0,
+                    departure,
+                })
+                .collect(),
+            warnings,
+        })
+    }
+}
+
+pub struct LineNode(TrainLine);
+
+#[Object(name = "TrainLine")]
+impl LineNode {
+    async fn id(&self) -> &str {
+        self.0.id()
+    }
+
+    async fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    #[graphql(complexity = "list_complexity(station_count(), child_complexity)")]
+    async fn stations(&self) -> Vec<StationNode> {
+        Station::iter()
+            .filter(|station| station.line().contains(&self.0))
+            .map(StationNode)
+            .collect()
+    }
+}
+
+pub struct WarningNode(DataWarning);
+
+#[Object(name = "DataWarning")]
+impl WarningNode {
+    /// Null when the warning concerns the whole response.
+    async fn row(&self) -> Option<usize> {
+        self.0.row
+    }
+
+    async fn skipped(&self) -> bool {
+        self.0.skipped
+    }
+
+    async fn message(&self) -> &str {
+        &self.0.message
+    }
+}
+
+fn warning_nodes(warnings: &[DataWarning]) -> Vec<WarningNode> {
+    warnings.iter().cloned().map(WarningNode).collect()
+}
+
+pub struct DepartureListNode {
+    items: Vec<DepartureNode>,
+    warnings: Vec<DataWarning>,
+}
+
+#[Object(name = "DepartureList")]
+impl DepartureListNode {
+    async fn items(&self) -> &[DepartureNode] {
+        &self.items
+    }
+
+    async fn warnings(&self) -> Vec<WarningNode> {
+        warning_nodes(&self.warnings)
+    }
+}
+
+pub struct DepartureNode {
+    station: Station,
+    departure: StationSchedule,
+}
+
+#[Object(name = "Departure")]
+impl DepartureNode {
+    async fn train_id(&self) -> &str {
+        &self.departure.train_id
+    }
+
+    async fn route_name(&self) -> &str {
+        &self.departure.route_name
+    }
+
+    async fn time(&self) -> NaiveTime {
+        self.departure.time_est
+    }
+
+    #[graphql(complexity = "FETCH_COMPLEXITY + child_complexity")]
+    async fn train(&self, ctx: &Context<'_>) -> async_graphql::Result<TrainNode> {
+        load_train(ctx, &self.departure.train_id).await
+    }
+
+    /// Stops the train still makes after this departure.
+    #[graphql(complexity = "FETCH_COMPLEXITY + list_complexity(station_count(), child_complexity)")]
+    async fn remaining_stops(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<StopNode>> {
+        let train = load_train(ctx, &self.departure.train_id).await?;
+        // The two schedules may not agree to the second, and a train can call
+        // at a station twice, so board at its closest call to this departure.
+        let boarding = train
+            .stops
+            .iter()
+            .enumerate()
+            .filter(|(_, stop)| stop.station == self.station)
+            .min_by_key(|(_, stop)| (stop.time_est - self.departure.time_est).abs())
+            .map(|(boarding, _)| boarding);
+        Ok(match boarding {
+            Some(boarding) => train.stops[boarding + 1..]
+                .iter()
+                .cloned()
+                .map(StopNode)
+                .collect(),
+            None => vec![],
+        })
+    }
+}
+
+pub struct TrainNode {
+    id: String,
+    stops: Vec<TrainSchedule>,
+    warnings: Vec<DataWarning>,
+}
+
+#[Object(name = "Train")]
+impl TrainNode {
+    async fn id(&self) -> &str {
+        &self.id
+    }
+
+    #[graphql(complexity = "list_complexity(station_count(), child_complexity)")]
+    async fn stops(&self) -> Vec<StopNode> {
+        self.stops.iter().cloned().map(StopNode).collect()
+    }
+
+    async fn warnings(&self) -> Vec<WarningNode> {
+        warning_nodes(&self.warnings)
+    }
+}
+
+pub struct StopNode(TrainSchedule);
+
+#[Object(name = "TrainSchedule")]
+impl StopNode {
+    async fn train_id(&self) -> &str {
+        &self.0.train_id
+    }
+
+    async fn station(&self) -> StationNode {
+        StationNode(self.0.station)
+    }
+
+    /// Station id as upstream sent it, also for stations not known yet.
+    async fn station_id(&self) -> &str {
+        self.0.station_id()
+    }
+
+    async fn time(&self) -> NaiveTime {
+        self.0.time_est
+    }
+}
+
+pub struct StopTimeNode(StopTime);
+
+#[Object(name = "StopTime")]
+impl StopTimeNode {
+    async fn station(&self) -> StationNode {
+        StationNode(self.0.station)
+    }
+
+    async fn time(&self) -> NaiveTime {
+        self.0.time
+    }
+}
+
+pub struct LegNode(Leg);
+
+#[Object(name = "Leg")]
+impl LegNode {
+    async fn mode(&self) -> &str {
+        match self.0.mode {
+            LegMode::Train => "train",
+            LegMode::Walk => "walk",
+        }
+    }
+
+    async fn train_id(&self) -> Option<&str> {
+        self.0.train_id.as_deref()
+    }
+
+    async fn route_name(&self) -> Option<&str> {
+        self.0.route_name.as_deref()
+    }
+
+    async fn line(&self) -> Option<LineNode> {
+        self.0.line.map(LineNode)
+    }
+
+    async fn board(&self) -> StopTimeNode {
+        StopTimeNode(self.0.board.clone())
+    }
+
+    async fn alight(&self) -> StopTimeNode {
+        StopTimeNode(self.0.alight.clone())
+    }
+
+    #[graphql(complexity = "list_complexity(station_count(), child_complexity)")]
+    async fn stops(&self) -> Vec<StopTimeNode> {
+        self.0.stops.iter().cloned().map(StopTimeNode).collect()
+    }
+}
+
+pub struct JourneyNode(Journey);
+
+#[Object(name = "Journey")]
+impl JourneyNode {
+    async fn departure(&self) -> NaiveTime {
+        self.0.departure
+    }
+
+    async fn arrival(&self) -> NaiveTime {
+        self.0.arrival
+    }
+
+    async fn transfers(&self) -> usize {
+        self.0.transfers
+    }
+
+    /// Minutes from the first departure to the final arrival.
+    async fn total_duration(&self) -> i64 {
+        self.0.total_duration
+    }
+
+    /// Minutes spent waiting between legs.
+    async fn waiting_time(&self) -> i64 {
+        self.0.waiting_time
+    }
+
+    /// A journey rides each line about once, with a walk in between.
+    #[graphql(complexity = "list_complexity(2 * line_count(), child_complexity)")]
+    async fn legs(&self) -> Vec<LegNode> {
+        self.0.legs.iter().cloned().map(LegNode).collect()
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    #[graphql(complexity = "list_complexity(station_count(), child_complexity)")]
+    async fn stations(
+        &self,
+        line_id: Option<String>,
+        transit_station_only: Option<bool>,
+    ) -> async_graphql::Result<Vec<StationNode>> {
+        let line = line_id.as_deref().map(parse_line).transpose()?;
+        let transit_station_only = transit_station_only.unwrap_or_default();
+        Ok(Station::iter()
+            .filter(|station| line.is_none_or(|line| station.line().contains(&line)))
+            .filter(|station| !transit_station_only || station.is_transit_station())
+            .map(StationNode)
+            .collect())
+    }
+
+    async fn station(&self, id: String) -> async_graphql::Result<StationNode> {
+        Ok(StationNode(parse_station(&id)?))
+    }
+
+    #[graphql(complexity = "list_complexity(line_count(), child_complexity)")]
+    async fn lines(&self) -> Vec<LineNode> {
+        TrainLine::iter().map(LineNode).collect()
+    }
+
+    async fn line(&self, id: String) -> async_graphql::Result<LineNode> {
+        Ok(LineNode(parse_line(&id)?))
+    }
+
+    #[graphql(complexity = "FETCH_COMPLEXITY + child_complexity")]
+    async fn train(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<TrainNode> {
+        load_train(ctx, &id).await
+    }
+
+    /// The fastest journey leaving `stationFrom` from `timeFrom` onwards,
+    /// passing every station of `via` and avoiding `avoidStations` and
+    /// `avoidLines`.
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(complexity = "JOURNEY_COMPLEXITY + child_complexity")]
+    async fn journey(
+        &self,
+        ctx: &Context<'_>,
+        station_from: String,
+        station_to: String,
+        time_from: NaiveTime,
+        transit_duration: Option<i64>,
+        via: Option<Vec<String>>,
+        avoid_stations: Option<Vec<String>>,
+        avoid_lines: Option<Vec<String>>,
+    ) -> async_graphql::Result<JourneyNode> {
+        let constraint = RouteConstraint {
+            via: parse_all(via, "via")?,
+            avoid_stations: parse_all(avoid_stations, "avoidStations")?,
+            avoid_lines: parse_all(avoid_lines, "avoidLines")?,
+        };
+        let config = ctx.data_unchecked::<web::Data<AppConfig>>();
+        let upstream = ctx.data_unchecked::<web::Data<Upstream>>();
+        let (journey, _) = choose_fastest_path(
+            upstream,
+            parse_station(&station_from)?,
+            parse_station(&station_to)?,
+            time_from,
+            parse_transit_duration(transit_duration).map_err(|err| graphql_error(&err))?,
+            &constraint,
+            config.fetch_concurrency,
+        )
+        .await
+        .map_err(|err| graphql_error(&err))?;
+        Ok(JourneyNode(journey))
+    }
+}
+
+pub fn build_schema() -> KrlSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        .finish()
+}
+
+#[post("/graphql")]
+async fn graphql_query(
+    schema: web::Data<KrlSchema>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+    req: web::Json<async_graphql::Request>,
+) -> HttpResponse {
+    let train_loader = DataLoader::with_cache(
+        TrainScheduleLoader {
+            upstream: upstream.clone().into_inner(),
+            concurrency: config.fetch_concurrency,
+        },
+        rt::spawn,
+        HashMapCache::default(),
+    );
+    let station_loader = DataLoader::with_cache(
+        StationScheduleLoader {
+            upstream: upstream.clone().into_inner(),
+            concurrency: config.fetch_concurrency,
+        },
+        rt::spawn,
+        HashMapCache::default(),
+    );
+    let request = req
+        .into_inner()
+        .data(train_loader)
+        .data(station_loader)
+        .data(config)
+        .data(upstream);
+    HttpResponse::Ok().json(schema.execute(request).await)
+}
+
+#[get("/graphql")]
+async fn graphiql() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn errors(query: &str) -> Vec<String> {
+        let response = build_schema().execute(query).await;
+        response.errors.into_iter().map(|err| err.message).collect()
+    }
+
+    #[actix_web::test]
+    async fn fan_out_over_the_network_is_too_complex() {
+        let too_complex = vec!["Query is too complex.".to_string()];
+        assert_eq!(
+            errors("{ stations { departures { items { remainingStops { station { id } } } } } }")
+                .await,
+            too_complex
+        );
+        assert_eq!(
+            errors("{ stations { departures { items { trainId } } } }").await,
+            too_complex
+        );
+        assert_eq!(
+            errors("{ station(id: \"BOO\") { departures(limit: 50) { items { train { id } } } } }")
+                .await,
+            too_complex
+        );
+    }
+
+    #[actix_web::test]
+    async fn departure_limit_is_capped() {
+        assert_eq!(
+            errors("{ station(id: \"BOO\") { departures(limit: 51) { items { trainId } } } }")
+                .await,
+            vec!["limit cannot be more than 50".to_string()]
+        );
+    }
+
+    #[actix_web::test]
+    async fn unknown_constraint_ids_name_the_argument() {
+        let response = build_schema()
+            .execute(
+                "{ journey(stationFrom: \"BOO\", stationTo: \"MRI\", timeFrom: \"07:00:00\", \
+                 avoidLines: [\"X\"]) { departure } }",
+            )
+            .await;
+        let error = &response.errors[0];
+        assert_eq!(error.message, "Unknown value 'X' for avoidLines");
+        let extensions = error.extensions.as_ref().unwrap();
+        assert_eq!(
+            extensions.get("code"),
+            Some(&async_graphql::Value::from("invalid-parameter"))
+        );
+        assert_eq!(
+            extensions.get("parameter"),
+            Some(&async_graphql::Value::from("avoidLines"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn queries_without_fetches_are_allowed() {
+        assert!(errors("{ lines { id stations { id name lines { id } } } }")
+            .await
+            .is_empty());
+    }
+}
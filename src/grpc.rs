@@ -0,0 +1,394 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    str::FromStr,
+    sync::Arc,
+    thread,
+};
+
+use chrono::{Duration, NaiveTime};
+use strum::IntoEnumIterator;
+use tokio::net::TcpListener;
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+
+use crate::{
+    error::{parse_param, AppError, AppErrorType},
+    fetch::{
+        fetch_distance, fetch_fare, fetch_station_schedule_with_warnings,
+        fetch_train_schedule_with_warnings, Upstream,
+    },
+    line::TrainLine,
+    model::{self, LegMode},
+    pathfinder::{choose_fastest_path, RouteConstraint},
+    route::{parse_transit_duration, MAX_TRANSIT_MINUTES},
+    station::Station,
+};
+
+pub mod proto {
+    tonic::include_proto!("krl.v1");
+}
+
+use proto::krl_server::{Krl, KrlServer};
+
+const TIME_FORMAT: &str = "%H:%M:%S";
+
+impl From<AppError> for Status {
+    fn from(err: AppError) -> Self {
+        let message = err.message();
+        match err.error_type {
+            AppErrorType::NotFoundError => Status::not_found(message),
+            AppErrorType::UpstreamUnavailableError => Status::unavailable(message),
+            AppErrorType::UpstreamBadDataError => Status::internal(message),
+            AppErrorType::InvalidRequestParameter => Status::invalid_argument(message),
+            AppErrorType::NoRouteError => Status::not_found(message),
+            AppErrorType::TimeoutError => Status::deadline_exceeded(message),
+            AppErrorType::StorageError => Status::internal(message),
+            AppErrorType::InternalError => Status::internal(message),
+        }
+    }
+}
+
+fn parse_station(id: &str) -> Result<Station, Status> {
+    Station::from_str(id).map_err(|err| AppError::from(err).into())
+}
+
+fn parse_time(field: &str, time: &str) -> Result<NaiveTime, Status> {
+    NaiveTime::parse_from_str(time, TIME_FORMAT)
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .map_err(|_| Status::invalid_argument(format!("{} must be formatted as HH:MM:SS", field)))
+}
+
+fn parse_transit(minutes: i64) -> Result<Duration, Status> {
+    parse_transit_duration(Some(minutes)).map_err(|_| {
+        Status::invalid_argument(format!(
+            "transit_duration must be between 0 and {} minutes",
+            MAX_TRANSIT_MINUTES
+        ))
+    })
+}
+
+fn format_time(time: NaiveTime) -> String {
+    time.format(TIME_FORMAT).to_string()
+}
+
+fn station_message(station: Station) -> proto::Station {
+    proto::Station {
+        id: station.id().to_string(),
+        name: station.name().to_string(),
+        line_ids: station
+            .line()
+            .iter()
+            .map(|line| line.id().to_string())
+            .collect(),
+        transit_station: station.is_transit_station(),
+    }
+}
+
+fn stop_time_message(stop: &model::StopTime) -> proto::StopTime {
+    proto::StopTime {
+        station_id: stop.station.id().to_string(),
+        time: format_time(stop.time),
+    }
+}
+
+fn warning_messages(warnings: Vec<model::DataWarning>) -> Vec<proto::DataWarning> {
+    warnings
+        .into_iter()
+        .map(|warning| proto::DataWarning {
+            row: warning.row.map(|row| row as u32),
+            skipped: warning.skipped,
+            message: warning.message,
+        })
+        .collect()
+}
+
+fn journey_message(journey: &model::Journey) -> proto::Journey {
+    proto::Journey {
+        departure: format_time(journey.departure),
+        arrival: format_time(journey.arrival),
+        legs: journey
+            .legs
+            .iter()
+            .map(|leg| proto::Leg {
+                mode: match leg.mode {
+                    LegMode::Train => "train".into(),
+                    LegMode::Walk => "walk".into(),
+                },
+                train_id: leg.train_id.clone(),
+                route_name: leg.route_name.clone(),
+                line_id: leg.line.map(|line| line.id().to_string()),
+                board: Some(stop_time_message(&leg.board)),
+                alight: Some(stop_time_message(&leg.alight)),
+                stops: leg.stops.iter().map(stop_time_message).collect(),
+            })
+            .collect(),
+        transfers: journey.transfers as u32,
+        total_duration: journey.total_duration,
+        waiting_time: journey.waiting_time,
+    }
+}
+
+fn parse_all<T: FromStr<Err = strum::ParseError>>(
+    ids: &[String],
+    field: &str,
+) -> Result<Vec<T>, Status> {
+    ids.iter()
+        .map(|id| parse_param(id, field).map_err(Status::from))
+        .collect()
+}
+
+pub struct KrlService {
+    upstream: Arc<Upstream>,
+    concurrency: usize,
+}
+
+#[tonic::async_trait]
+impl Krl for KrlService {
+    async fn list_stations(
+        &self,
+        request: Request<proto::ListStationsRequest>,
+    ) -> Result<Response<proto::ListStationsResponse>, Status> {
+        let request = request.into_inner();
+        let line = match &request.line_id {
+            Some(line) => Some(TrainLine::from_str(line).map_err(AppError::from)?),
+            None => None,
+        };
+        let stations = Station::iter()
+            .filter(|station| line.is_none_or(|line| station.line().contains(&line)))
+            .filter(|station| !request.transit_station_only || station.is_transit_station())
+            .map(station_message)
+            .collect();
+        Ok(Response::new(proto::ListStationsResponse { stations }))
+    }
+
+    async fn get_station(
+        &self,
+        request: Request<proto::GetStationRequest>,
+    ) -> Result<Response<proto::Station>, Status> {
+        let station = parse_station(&request.into_inner().id)?;
+        Ok(Response::new(station_message(station)))
+    }
+
+    async fn get_station_schedule(
+        &self,
+        request: Request<proto::StationScheduleRequest>,
+    ) -> Result<Response<proto::StationScheduleResponse>, Status> {
+        let request = request.into_inner();
+        let station = parse_station(&request.station_id)?;
+        let time_from = match &request.time_from {
+            Some(time) => parse_time("time_from", time)?,
+            None => NaiveTime::MIN,
+        };
+        let time_to = match &request.time_to {
+            Some(time) => parse_time("time_to", time)?,
+            None => NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+        };
+        let (departures, warnings) =
+            fetch_station_schedule_with_warnings(&self.upstream, station, time_from, time_to)
+                .await?;
+        let departures = departures
+            .into_iter()
+            .map(|schedule| proto::Departure {
+                train_id: schedule.train_id,
+                route_name: schedule.route_name,
+                time_est: format_time(schedule.time_est),
+            })
+            .collect();
+        Ok(Response::new(proto::StationScheduleResponse {
+            departures,
+            warnings: warning_messages(warnings),
+        }))
+    }
+
+    async fn get_train_schedule(
+        &self,
+        request: Request<proto::TrainScheduleRequest>,
+    ) -> Result<Response<proto::TrainScheduleResponse>, Status> {
+        let train_id = request.into_inner().train_id;
+        let (stops, warnings) =
+            fetch_train_schedule_with_warnings(&self.upstream, &train_id).await?;
+        let stops = stops
+            .into_iter()
+            .map(|schedule| proto::TrainStop {
+                station_id: schedule.station_id().to_string(),
+                station_name: schedule.station.name().to_string(),
+                time_est: format_time(schedule.time_est),
+            })
+            .collect();
+        Ok(Response::new(proto::TrainScheduleResponse {
+            train_id,
+            stops,
+            warnings: warning_messages(warnings),
+        }))
+    }
+
+    async fn get_fare(
+        &self,
+        request: Request<proto::RouteInfoRequest>,
+    ) -> Result<Response<proto::FareResponse>, Status> {
+        let request = request.into_inner();
+        let station_from = parse_station(&request.station_from)?;
+        let station_to = parse_station(&request.station_to)?;
+        let fare = fetch_fare(&self.upstream, station_from, station_to).await?;
+        Ok(Response::new(proto::FareResponse {
+            fare: fare.fare.into(),
+            warnings: warning_messages(fare.warnings),
+        }))
+    }
+
+    async fn get_distance(
+        &self,
+        request: Request<proto::RouteInfoRequest>,
+    ) -> Result<Response<proto::DistanceResponse>, Status> {
+        let request = request.into_inner();
+        let station_from = parse_station(&request.station_from)?;
+        let station_to = parse_station(&request.station_to)?;
+        let distance = fetch_distance(&self.upstream, station_from, station_to).await?;
+        Ok(Response::new(proto::DistanceResponse {
+            distance: distance.distance,
+            warnings: warning_messages(distance.warnings),
+        }))
+    }
+
+    async fn plan_journey(
+        &self,
+        request: Request<proto::PlanJourneyRequest>,
+    ) -> Result<Response<proto::PlanJourneyResponse>, Status> {
+        let request = request.into_inner();
+        let station_from = parse_station(&request.station_from)?;
+        let station_to = parse_station(&request.station_to)?;
+        let time_from = parse_time("time_from", &request.time_from)?;
+        let constraint = RouteConstraint {
+            via: parse_all(&request.via, "via")?,
+            avoid_stations: parse_all(&request.avoid_stations, "avoid_stations")?,
+            avoid_lines: parse_all(&request.avoid_lines, "avoid_lines")?,
+        };
+        let (journey, skipped_paths) = choose_fastest_path(
+            &self.upstream,
+            station_from,
+            station_to,
+            time_from,
+            parse_transit(request.transit_duration)?,
+            &constraint,
+            self.concurrency,
+        )
+        .await?;
+        Ok(Response::new(proto::PlanJourneyResponse {
+            journey: Some(journey_message(&journey)),
+            skipped_paths: skipped_paths
+                .into_iter()
+                .map(|skipped| proto::SkippedPath {
+                    station_ids: skipped
+                        .path
+                        .iter()
+                        .map(|station| station.id().to_string())
+                        .collect(),
+                    reason: skipped.reason,
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// Binds `address` and serves gRPC from a multi-threaded runtime of its own,
+/// so it never competes with the HTTP workers. Binding happens before this
+/// returns, so an address in use stops startup instead of going unnoticed.
+pub fn start(address: SocketAddr, upstream: Arc<Upstream>, concurrency: usize) -> io::Result<()> {
+    let listener = StdTcpListener::bind(address).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("Failed to bind gRPC to {}: {}", address, err),
+        )
+    })?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .thread_name("grpc")
+        .enable_all()
+        .build()?;
+    thread::Builder::new().name("grpc".into()).spawn(move || {
+        let result = runtime.block_on(async move {
+            let incoming = TcpIncoming::from(TcpListener::from_std(listener)?);
+            Server::builder()
+                .add_service(KrlServer::new(KrlService {
+                    upstream,
+                    concurrency,
+                }))
+                .serve_with_incoming(incoming)
+                .await
+                .map_err(io::Error::other)
+        });
+        if let Err(err) = result {
+            log::error!("gRPC server on {} stopped: {}", address, err);
+        }
+    })?;
+    log::info!("gRPC listening on {}", address);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    fn status_code(error_type: AppErrorType) -> Code {
+        Status::from(AppError {
+            message: Some("message".into()),
+            cause: Some("cause".into()),
+            error_type,
+            parameter: None,
+        })
+        .code()
+    }
+
+    #[test]
+    fn app_errors_map_to_grpc_codes() {
+        assert_eq!(status_code(AppErrorType::NotFoundError), Code::NotFound);
+        assert_eq!(
+            status_code(AppErrorType::UpstreamUnavailableError),
+            Code::Unavailable
+        );
+        assert_eq!(
+            status_code(AppErrorType::UpstreamBadDataError),
+            Code::Internal
+        );
+        assert_eq!(
+            status_code(AppErrorType::InvalidRequestParameter),
+            Code::InvalidArgument
+        );
+        assert_eq!(status_code(AppErrorType::NoRouteError), Code::NotFound);
+        assert_eq!(
+            status_code(AppErrorType::TimeoutError),
+            Code::DeadlineExceeded
+        );
+        assert_eq!(status_code(AppErrorType::StorageError), Code::Internal);
+        assert_eq!(status_code(AppErrorType::InternalError), Code::Internal);
+    }
+
+    #[test]
+    fn status_carries_the_message_not_the_cause() {
+        let status = Status::from(AppError {
+            message: Some("Station from and station cannot be the same".into()),
+            cause: Some("internal detail".into()),
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: None,
+        });
+        assert_eq!(
+            status.message(),
+            "Station from and station cannot be the same"
+        );
+    }
+
+    #[test]
+    fn transit_duration_out_of_range_is_an_invalid_argument() {
+        assert_eq!(parse_transit(5).unwrap(), Duration::minutes(5));
+        for minutes in [-1, MAX_TRANSIT_MINUTES + 1, i64::MAX] {
+            let status = parse_transit(minutes).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert!(status.message().starts_with("transit_duration"));
+        }
+    }
+}
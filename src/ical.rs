@@ -0,0 +1,213 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Utc, Weekday};
+
+use crate::model::{Journey, LegMode};
+
+const DATETIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+const TIMEZONE: &str = "Asia/Jakarta";
+
+/// Escapes a TEXT value as required by RFC 5545 section 3.3.11.
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Folds a content line at 75 octets, never splitting a UTF-8 character.
+fn fold_line(line: &str, out: &mut String) {
+    let mut width = 0;
+    for ch in line.chars() {
+        if width + ch.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(ch);
+        width += ch.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn byday(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+fn describe(journey: &Journey) -> String {
+    let mut lines = vec![];
+    for (i, leg) in journey.legs.iter().enumerate() {
+        if i > 0 {
+            let wait = (leg.board.time - journey.legs[i - 1].alight.time).num_minutes();
+            if leg.mode == LegMode::Train && wait > 0 {
+                lines.push(format!(
+                    "Transfer at {}, {} min wait",
+                    leg.board.station.name(),
+                    wait
+                ));
+            }
+        }
+        match leg.mode {
+            LegMode::Train => lines.push(format!(
+                "Train {} ({}): {} {} - {} {}",
+                leg.train_id.as_deref().unwrap_or_default(),
+                leg.route_name.as_deref().unwrap_or_default(),
+                leg.board.station.name(),
+                leg.board.time.format("%H:%M"),
+                leg.alight.station.name(),
+                leg.alight.time.format("%H:%M"),
+            )),
+            LegMode::Walk => lines.push(format!(
+                "Walk {} - {} ({} min)",
+                leg.board.station.name(),
+                leg.alight.station.name(),
+                (leg.alight.time - leg.board.time).num_minutes(),
+            )),
+        }
+    }
+    lines.join("\n")
+}
+
+/// Renders a journey as a single VEVENT on `date`, repeating weekly on
+/// `weekdays` when any are given, with a display alarm `alarm_minutes`
+/// before departure. A repeating event starts on the first of `weekdays` on
+/// or after `date`, as RFC 5545 counts DTSTART as an occurrence even when it
+/// does not match BYDAY.
+pub fn journey_calendar(
+    journey: &Journey,
+    date: NaiveDate,
+    weekdays: &[Weekday],
+    alarm_minutes: i64,
+) -> String {
+    let date = date
+        .iter_days()
+        .take(7)
+        .find(|day| weekdays.is_empty() || weekdays.contains(&day.weekday()))
+        .unwrap_or(date);
+    let start = NaiveDateTime::new(date, journey.departure);
+    let mut end = NaiveDateTime::new(date, journey.arrival);
+    if end < start {
+        end += Duration::days(1);
+    }
+    let from_name = journey
+        .legs
+        .first()
+        .map(|leg| leg.board.station.name())
+        .unwrap_or_default();
+    let to_name = journey
+        .legs
+        .last()
+        .map(|leg| leg.alight.station.name())
+        .unwrap_or_default();
+    let train_ids = journey
+        .legs
+        .iter()
+        .filter_map(|leg| leg.train_id.as_deref())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".into(),
+        "PRODID:-//krl-service//journey//EN".into(),
+        "CALSCALE:GREGORIAN".into(),
+        "BEGIN:VTIMEZONE".into(),
+        format!("TZID:{}", TIMEZONE),
+        "BEGIN:STANDARD".into(),
+        "DTSTART:19700101T000000".into(),
+        "TZOFFSETFROM:+0700".into(),
+        "TZOFFSETTO:+0700".into(),
+        "TZNAME:WIB".into(),
+        "END:STANDARD".into(),
+        "END:VTIMEZONE".into(),
+        "BEGIN:VEVENT".into(),
+        format!(
+            "UID:{}-{}-{}@krl-service",
+            start.format(DATETIME_FORMAT),
+            train_ids,
+            if weekdays.is_empty() {
+                "once"
+            } else {
+                "weekly"
+            }
+        ),
+        format!("DTSTAMP:{}Z", Utc::now().format(DATETIME_FORMAT)),
+        format!(
+            "DTSTART;TZID={}:{}",
+            TIMEZONE,
+            start.format(DATETIME_FORMAT)
+        ),
+        format!("DTEND;TZID={}:{}", TIMEZONE, end.format(DATETIME_FORMAT)),
+    ];
+    if !weekdays.is_empty() {
+        let days = weekdays.iter().map(|day| byday(*day)).collect::<Vec<_>>();
+        lines.push(format!("RRULE:FREQ=WEEKLY;BYDAY={}", days.join(",")));
+    }
+    lines.extend([
+        format!(
+            "SUMMARY:{}",
+            escape_text(&format!("KRL {} to {}", from_name, to_name))
+        ),
+        format!("LOCATION:{}", escape_text(from_name)),
+        format!("DESCRIPTION:{}", escape_text(&describe(journey))),
+        "BEGIN:VALARM".into(),
+        "ACTION:DISPLAY".into(),
+        format!("TRIGGER:-PT{}M", alarm_minutes),
+        format!(
+            "DESCRIPTION:{}",
+            escape_text(&format!(
+                "Train leaves {} at {}",
+                from_name,
+                journey.departure.format("%H:%M")
+            ))
+        ),
+        "END:VALARM".into(),
+        "END:VEVENT".into(),
+        "END:VCALENDAR".into(),
+    ]);
+
+    let mut calendar = String::new();
+    for line in lines {
+        fold_line(&line, &mut calendar);
+    }
+    calendar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::Leg, station::Station, test_util::run};
+
+    fn dtstart(weekdays: &[Weekday]) -> String {
+        let leg = Leg::train(
+            "BOGOR-JAKARTAKOTA".into(),
+            None,
+            run(&[(Station::BOO, "06:00"), (Station::DP, "06:30")]),
+        )
+        .unwrap();
+        let journey = Journey::from_legs(vec![leg]).unwrap();
+        // A Saturday.
+        let date = NaiveDate::from_ymd_opt(2024, 1, 6).unwrap();
+        journey_calendar(&journey, date, weekdays, 10)
+            .lines()
+            .find_map(|line| line.strip_prefix("DTSTART;TZID=Asia/Jakarta:"))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn weekly_events_start_on_a_listed_weekday() {
+        assert_eq!(dtstart(&[]), "20240106T060000");
+        assert_eq!(dtstart(&[Weekday::Sat, Weekday::Sun]), "20240106T060000");
+        assert_eq!(
+            dtstart(&[Weekday::Mon, Weekday::Tue, Weekday::Wed]),
+            "20240108T060000"
+        );
+        assert_eq!(dtstart(&[Weekday::Fri]), "20240112T060000");
+    }
+}
@@ -2,10 +2,11 @@ use serde::Serialize;
 use serde_json::{Map, Value};
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString};
+use utoipa::ToSchema;
 
 use crate::station::Station;
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, EnumString, EnumIter)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, ToSchema, EnumString, EnumIter)]
 pub enum TrainLine {
     B,
     C,
@@ -86,6 +87,25 @@ impl TrainLine {
         }
     }
 
+    /// The line a train runs on, judged by its calling stations: the line
+    /// serving most of them, the first declared one on a tie. Stations this
+    /// service does not know are ignored.
+    pub fn of_run(stations: impl IntoIterator<Item = Station>) -> Option<TrainLine> {
+        let stations = stations.into_iter().collect::<Vec<_>>();
+        TrainLine::iter()
+            .map(|line| {
+                let served = stations
+                    .iter()
+                    .filter(|station| station.line().contains(&line))
+                    .count();
+                (line, served)
+            })
+            .filter(|(_, served)| *served > 0)
+            .rev()
+            .max_by_key(|(_, served)| *served)
+            .map(|(line, _)| line)
+    }
+
     pub fn map_name_to_id() -> Map<String, Value> {
         let mut map = Map::new();
         for line in TrainLine::iter() {
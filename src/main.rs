@@ -1,35 +1,143 @@
 use std::net::ToSocketAddrs;
 
-use actix_web::{App, HttpServer};
+use actix_web::{middleware::from_fn, web, App, HttpServer};
+use config::AppConfig;
+use departure::DepartureHub;
+use error::{json_error_handler, path_error_handler};
+use fetch::Upstream;
+use graphql::{build_schema, graphiql, graphql_query};
+use openapi::{openapi_json, swagger_ui};
 use route::{
-    distance, get_fastest_route, get_transit_route, station_list, station_schedule, train_fare,
-    train_schedule, line_list,
+    batch_queries, create_subscription, data_quality, delete_subscription, departures_stream,
+    distance, first_last_train, get_fastest_route, get_route_profile, get_transit_route, headways,
+    history_dates, history_station_schedule, history_train_schedule, journey_ics, line_list,
+    routes, station_list, station_schedule, subscription_detail, subscription_list,
+    timetable_changes, train_fare, train_position, train_positions, train_schedule,
+    update_subscription,
 };
+use store::Store;
+use subscription::{run_scheduler, SubscriptionStore};
+use timetable::run_snapshotter;
 
+mod analytics;
+mod batch;
+mod config;
+mod departure;
 mod error;
 mod fetch;
+mod format;
+mod graphql;
+mod grpc;
+mod ical;
 mod line;
 mod model;
+mod openapi;
 mod pathfinder;
+mod position;
+mod quality;
 mod route;
 mod station;
+mod store;
+mod subscription;
+#[cfg(test)]
+mod test_util;
+mod timetable;
+mod v1;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
     let args: Vec<String> = std::env::args().collect();
 
-    let ip_port = &args[1].to_socket_addrs()?.next().unwrap();
+    let Some(address) = args.get(1) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "usage: krl-service <address:port>",
+        ));
+    };
+    let ip_port = &address.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} does not resolve to any address", address),
+        )
+    })?;
 
-    HttpServer::new(|| {
+    let config = web::Data::new(AppConfig::from_env()?);
+    let store =
+        Store::open(&config.database_file).map_err(|err| std::io::Error::other(err.detail()))?;
+    let upstream = web::Data::new(
+        Upstream::new(config.upstream_max_requests, store.clone())
+            .map_err(|err| std::io::Error::other(err.detail()))?,
+    );
+    let departure_hub = web::Data::new(DepartureHub::new(
+        config.departure_poll_seconds,
+        upstream.clone().into_inner(),
+    ));
+    let subscription_store = web::Data::new(SubscriptionStore::new(store.clone()));
+    actix_web::rt::spawn(run_scheduler(
+        upstream.clone().into_inner(),
+        subscription_store.clone().into_inner(),
+        config.alert_lead_minutes,
+        config.fetch_concurrency,
+    ));
+    actix_web::rt::spawn(run_snapshotter(
+        upstream.clone().into_inner(),
+        store.clone(),
+        config.fetch_concurrency,
+        config.timetable_webhook_url.clone(),
+    ));
+
+    grpc::start(
+        config.grpc_address,
+        upstream.clone().into_inner(),
+        config.fetch_concurrency,
+    )?;
+    let schema = web::Data::new(build_schema());
+    let store = web::Data::new(store);
+
+    HttpServer::new(move || {
         App::new()
+            .app_data(config.clone())
+            .app_data(upstream.clone())
+            .app_data(store.clone())
+            .app_data(departure_hub.clone())
+            .app_data(subscription_store.clone())
+            .app_data(schema.clone())
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .app_data(web::PathConfig::default().error_handler(path_error_handler))
+            .wrap(from_fn(v1::deprecation_headers))
+            .configure(v1::configure)
             .service(station_schedule)
+            .service(departures_stream)
             .service(station_list)
             .service(train_schedule)
+            .service(train_position)
+            .service(train_positions)
             .service(train_fare)
             .service(distance)
             .service(get_fastest_route)
+            .service(get_route_profile)
+            .service(routes)
+            .service(first_last_train)
+            .service(journey_ics)
             .service(get_transit_route)
             .service(line_list)
+            .service(create_subscription)
+            .service(subscription_list)
+            .service(subscription_detail)
+            .service(update_subscription)
+            .service(delete_subscription)
+            .service(history_dates)
+            .service(history_station_schedule)
+            .service(history_train_schedule)
+            .service(timetable_changes)
+            .service(headways)
+            .service(batch_queries)
+            .service(data_quality)
+            .service(graphql_query)
+            .service(graphiql)
+            .service(openapi_json)
+            .service(swagger_ui())
     })
     .bind(ip_port)?
     .run()
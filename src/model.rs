@@ -1,34 +1,145 @@
 use std::str::FromStr;
 
-use chrono::NaiveTime;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::{de, de::DeserializeOwned, Deserializer};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
 
 use crate::{
     error::{AppError, AppErrorType},
+    line::TrainLine,
     station::Station,
 };
 
 #[derive(Deserialize, Debug)]
 pub struct StationScheduleDTO {
+    #[serde(deserialize_with = "lenient_string")]
     train_id: String,
+    #[serde(deserialize_with = "lenient_string")]
     route_name: String,
+    #[serde(deserialize_with = "lenient_string")]
     time_est: String,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TrainScheduleDTO {
+    #[serde(deserialize_with = "lenient_string")]
     train_id: String,
+    #[serde(deserialize_with = "lenient_string")]
     station_id: String,
+    #[serde(deserialize_with = "lenient_string")]
     time_est: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Clone, Debug)]
 pub struct RouteInfoDTO {
-    fare: u16,
-    distance: String,
+    #[serde(deserialize_with = "lenient_u16")]
+    pub fare: u16,
+    #[serde(deserialize_with = "lenient_string")]
+    pub distance: String,
+}
+
+/// Accepts a string or a number, as upstream is not consistent about which
+/// one it sends for ids and times.
+fn lenient_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let value: Value = serde::Deserialize::deserialize(deserializer)?;
+    match value {
+        Value::String(value) => Ok(value.trim().to_string()),
+        Value::Number(value) => Ok(value.to_string()),
+        value => Err(de::Error::custom(format!(
+            "expected a string, found {}",
+            value
+        ))),
+    }
+}
+
+fn lenient_u16<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let value = lenient_string(deserializer)?;
+    value
+        .parse()
+        .map_err(|_| de::Error::custom(format!("expected a number, found {:?}", value)))
+}
+
+/// An upstream row that was skipped, or kept with a placeholder, because it
+/// did not have the expected shape, or a response served from the store
+/// because KRL failed.
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct DataWarning {
+    /// Position of the row in the upstream response, from 0. Null when the
+    /// warning concerns the whole response.
+    pub row: Option<usize>,
+    /// Whether the row was left out of the response.
+    pub skipped: bool,
+    pub message: String,
+}
+
+impl DataWarning {
+    /// KRL failed, so the response holds what was stored on `date`, which
+    /// may be out of date.
+    pub fn served_from_store(date: NaiveDate) -> Self {
+        Self {
+            row: None,
+            skipped: false,
+            message: format!("KRL is unavailable, served from data stored on {}", date),
+        }
+    }
 }
 
-#[derive(Serialize)]
+/// Rows parsed from an upstream response, with warnings for the rows that
+/// were skipped or patched.
+pub type ParsedRows<T> = (Vec<T>, Vec<DataWarning>);
+
+/// Converts upstream rows one at a time, so a malformed row is skipped and
+/// reported instead of failing the whole response. `convert` may note
+/// problems it worked around without skipping the row.
+fn from_rows<D, T>(
+    rows: Vec<Value>,
+    convert: impl Fn(D, &mut Vec<String>) -> Result<T, AppError>,
+) -> ParsedRows<T>
+where
+    D: DeserializeOwned,
+{
+    let mut parsed = Vec::with_capacity(rows.len());
+    let mut warnings = vec![];
+    for (row, value) in rows.into_iter().enumerate() {
+        let mut notes = vec![];
+        let result = serde_json::from_value::<D>(value)
+            .map_err(|err| err.to_string())
+            .and_then(|dto| convert(dto, &mut notes).map_err(|err| err.detail()));
+        warnings.extend(notes.into_iter().map(|message| DataWarning {
+            row: Some(row),
+            skipped: false,
+            message,
+        }));
+        match result {
+            Ok(value) => parsed.push(value),
+            Err(message) => warnings.push(DataWarning {
+                row: Some(row),
+                skipped: true,
+                message,
+            }),
+        }
+    }
+    (parsed, warnings)
+}
+
+impl RouteInfoDTO {
+    pub fn from_rows(rows: Vec<Value>) -> ParsedRows<Self> {
+        from_rows(rows, |dto, _| Ok(dto))
+    }
+}
+
+fn missing_train_id() -> AppError {
+    AppError {
+        message: Some("Missing train id".into()),
+        cause: None,
+        error_type: AppErrorType::UpstreamBadDataError,
+        parameter: None,
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq, Eq)]
 pub struct StationSchedule {
     pub train_id: String,
     pub route_name: String,
@@ -37,94 +148,579 @@ pub struct StationSchedule {
 
 impl StationSchedule {
     pub fn from_dto(value: StationScheduleDTO) -> Result<Self, AppError> {
+        if value.train_id.is_empty() {
+            return Err(missing_train_id());
+        }
         let time_est = to_naive_time_hm(value.time_est)?;
 
-        return Ok(Self {
+        Ok(Self {
             train_id: value.train_id,
             route_name: value.route_name,
             time_est,
-        });
+        })
+    }
+
+    pub fn from_rows(rows: Vec<Value>) -> ParsedRows<Self> {
+        from_rows(rows, |dto, _| Self::from_dto(dto))
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, ToSchema, Clone, Debug)]
 pub struct TrainSchedule {
     pub train_id: String,
     pub station: Station,
+    /// Upstream id of a station this service does not know yet, in which
+    /// case `station` is the unknown placeholder.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unknown_station_id: Option<String>,
     pub time_est: NaiveTime,
 }
 
 impl TrainSchedule {
+    /// Unknown station ids become [`Station::Unknown`] rather than failing,
+    /// so a new station does not hide the rest of the train's stops. The id
+    /// itself is kept in `unknown_station_id`.
     pub fn from_dto(value: TrainScheduleDTO) -> Result<Self, AppError> {
+        if value.train_id.is_empty() {
+            return Err(missing_train_id());
+        }
         let train_id = value.train_id;
-        let station = Station::from_str(&value.station_id)?;
+        let (station, unknown_station_id) = match Station::from_str(&value.station_id) {
+            Ok(station) => (station, None),
+            Err(_) => (Station::Unknown, Some(value.station_id)),
+        };
         let time_est = to_naive_time_hm(value.time_est)?;
 
-        return Ok(Self {
+        Ok(Self {
             train_id,
             station,
+            unknown_station_id,
             time_est,
-        });
+        })
     }
+
+    /// Station id as upstream sent it, known to this service or not.
+    pub fn station_id(&self) -> &str {
+        self.unknown_station_id
+            .as_deref()
+            .unwrap_or(self.station.id())
+    }
+
+    pub fn from_rows(rows: Vec<Value>) -> ParsedRows<Self> {
+        from_rows(rows, |dto: TrainScheduleDTO, notes| {
+            if Station::from_str(&dto.station_id).is_err() {
+                notes.push(format!("Unknown station id {:?}", dto.station_id));
+            }
+            Self::from_dto(dto)
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LegMode {
+    Train,
+    Walk,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct StopTime {
+    pub station: Station,
+    pub time: NaiveTime,
+}
+
+/// One ride on a single train, or one walk between nearby stations.
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct Leg {
+    pub mode: LegMode,
+    /// Null for walking legs.
+    pub train_id: Option<String>,
+    /// Null for walking legs.
+    pub route_name: Option<String>,
+    /// Null for walking legs.
+    pub line: Option<TrainLine>,
+    pub board: StopTime,
+    pub alight: StopTime,
+    /// Every stop from boarding to alighting, both included. Empty for
+    /// walking legs.
+    pub stops: Vec<StopTime>,
+}
+
+impl Leg {
+    pub fn train(
+        route_name: String,
+        line: Option<TrainLine>,
+        schedules: Vec<TrainSchedule>,
+    ) -> Option<Self> {
+        let first = schedules.first()?;
+        let last = schedules.last()?;
+
+        Some(Self {
+            mode: LegMode::Train,
+            train_id: Some(first.train_id.clone()),
+            route_name: Some(route_name),
+            line,
+            board: StopTime {
+                station: first.station,
+                time: first.time_est,
+            },
+            alight: StopTime {
+                station: last.station,
+                time: last.time_est,
+            },
+            stops: schedules
+                .into_iter()
+                .map(|schedule| StopTime {
+                    station: schedule.station,
+                    time: schedule.time_est,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn walk(from: Station, to: Station, departure: NaiveTime, arrival: NaiveTime) -> Self {
+        Self {
+            mode: LegMode::Walk,
+            train_id: None,
+            route_name: None,
+            line: None,
+            board: StopTime {
+                station: from,
+                time: departure,
+            },
+            alight: StopTime {
+                station: to,
+                time: arrival,
+            },
+            stops: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct Journey {
+    pub departure: NaiveTime,
+    pub arrival: NaiveTime,
+    pub legs: Vec<Leg>,
+    pub transfers: usize,
+    /// Minutes from the first departure to the final arrival.
+    pub total_duration: i64,
+    /// Minutes spent waiting between legs, walking excluded.
+    pub waiting_time: i64,
+}
+
+impl Journey {
+    pub fn from_legs(legs: Vec<Leg>) -> Option<Self> {
+        let departure = legs.first()?.board.time;
+        let arrival = legs.last()?.alight.time;
+        let train_ids = legs
+            .iter()
+            .filter_map(|leg| leg.train_id.as_deref())
+            .collect::<Vec<_>>();
+        let transfers = train_ids
+            .windows(2)
+            .filter(|pair| pair[0] != pair[1])
+            .count();
+        let waiting_time = legs
+            .windows(2)
+            .map(|pair| elapsed(pair[0].alight.time, pair[1].board.time).num_minutes())
+            .sum();
+
+        Some(Self {
+            departure,
+            arrival,
+            transfers,
+            total_duration: elapsed(departure, arrival).num_minutes(),
+            waiting_time,
+            legs,
+        })
+    }
+
+    pub fn has_train(&self) -> bool {
+        self.legs.iter().any(|leg| leg.mode == LegMode::Train)
+    }
+
+    /// A journey dominates another when it leaves no earlier, arrives no later
+    /// and needs no more transfers, while being strictly better in one of them.
+    pub fn dominates(&self, other: &Self) -> bool {
+        self.departure >= other.departure
+            && self.arrival <= other.arrival
+            && self.transfers <= other.transfers
+            && (self.departure > other.departure
+                || self.arrival < other.arrival
+                || self.transfers < other.transfers)
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct SkippedPath {
+    pub path: Vec<Station>,
+    pub reason: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+pub struct FastestRoute {
+    pub journey: Journey,
+    pub skipped_paths: Vec<SkippedPath>,
+}
+
+/// The first journey along every candidate station path.
+#[derive(Serialize, ToSchema)]
+pub struct TransitRoutes {
+    pub journeys: Vec<Journey>,
+    pub skipped_paths: Vec<SkippedPath>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FirstLastTrain {
+    /// Service day the answer is for, always today. Its last train may run
+    /// past midnight.
+    pub date: NaiveDate,
+    pub first_train: Journey,
+    pub last_train: Journey,
+    pub skipped_paths: Vec<SkippedPath>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RouteProfile {
+    pub journeys: Vec<Journey>,
+    pub skipped_paths: Vec<SkippedPath>,
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteTag {
+    Faster,
+    EarlierArrival,
+    LaterDeparture,
+    FewerTransfers,
+    Cheaper,
+}
+
+/// A journey with how it compares to the best ranked one.
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct RankedJourney {
+    pub journey: Journey,
+    /// Total fare in Rupiah. KRL charges per trip rather than per train, and
+    /// a walk between stations starts a new trip. Null when a fare could not
+    /// be fetched.
+    pub fare: Option<u32>,
+    pub tags: Vec<RouteTag>,
+    pub summary: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RouteAlternatives {
+    pub journeys: Vec<RankedJourney>,
+    pub skipped_paths: Vec<SkippedPath>,
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct Fare {
     pub fare: u16,
+    pub warnings: Vec<DataWarning>,
 }
 
 impl From<RouteInfoDTO> for Fare {
     fn from(value: RouteInfoDTO) -> Self {
-        Self { fare: value.fare }
+        Self {
+            fare: value.fare,
+            warnings: vec![],
+        }
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct Distance {
     pub distance: f32,
+    pub warnings: Vec<DataWarning>,
 }
 
 impl From<RouteInfoDTO> for Distance {
     fn from(value: RouteInfoDTO) -> Self {
         Self {
             distance: value.distance.parse().unwrap_or(-1.),
+            warnings: vec![],
         }
     }
 }
 
+/// Departures of one station, as served by `/station-schedule`.
+#[derive(Serialize, ToSchema)]
+pub struct StationScheduleList {
+    pub schedules: Vec<StationSchedule>,
+    /// Upstream rows that were skipped or patched, and whether the
+    /// schedules came from the store.
+    pub warnings: Vec<DataWarning>,
+}
+
+/// Stops of one train, as served by `/train-schedule`.
+#[derive(Serialize, ToSchema)]
+pub struct TrainScheduleList {
+    pub schedules: Vec<TrainSchedule>,
+    /// Upstream rows that were skipped or patched, and whether the
+    /// schedules came from the store.
+    pub warnings: Vec<DataWarning>,
+}
+
+/// Current wall clock time in Jakarta (WIB, UTC+7, no daylight saving).
+pub fn jakarta_now() -> NaiveDateTime {
+    Utc::now().naive_utc() + Duration::hours(7)
+}
+
+/// KRL runs no trains in the small hours, so a service day is taken to start
+/// here. Trains running past midnight belong to the day before.
+pub const SERVICE_DAY_START: NaiveTime = NaiveTime::from_hms_opt(3, 0, 0).unwrap();
+
+/// Time from `from` until the clock next reads `to`. No ride or wait spans a
+/// whole day, so a `to` earlier than `from` is on the next day.
+pub fn elapsed(from: NaiveTime, to: NaiveTime) -> Duration {
+    let elapsed = to - from;
+    if elapsed < Duration::zero() {
+        elapsed + Duration::days(1)
+    } else {
+        elapsed
+    }
+}
+
+/// Service day `now` falls in, which is still the previous date in the small
+/// hours.
+pub fn service_date(now: NaiveDateTime) -> NaiveDate {
+    (now - (SERVICE_DAY_START - NaiveTime::MIN)).date()
+}
+
+/// How far into its service day `time` is, so that trains past midnight
+/// sort after the evening ones.
+pub fn service_time(time: NaiveTime) -> Duration {
+    elapsed(SERVICE_DAY_START, time)
+}
+
 pub fn to_naive_time_hm(time: String) -> Result<NaiveTime, AppError> {
     let err = AppError {
         message: Some("Invalid time format".into()),
-        cause: None,
-        error_type: AppErrorType::InvalidTimeFormat,
+        cause: Some(format!("Unexpected time {:?}", time)),
+        error_type: AppErrorType::UpstreamBadDataError,
+        parameter: None,
     };
 
     let time = time.split(":").collect::<Vec<&str>>();
-    let Ok(hour) = time[0].parse::<u32>() else {
+    let Some(Ok(hour)) = time.first().map(|hour| hour.parse::<u32>()) else {
         return Err(err);
     };
-    let Ok(min) = time[1].parse::<u32>() else {
+    let Some(Ok(min)) = time.get(1).map(|min| min.parse::<u32>()) else {
         return Err(err);
     };
     NaiveTime::from_hms_opt(hour, min, 0).ok_or(err)
 }
 
+#[allow(dead_code)]
 pub fn to_naive_time_hms(time: String) -> Result<NaiveTime, AppError> {
     let err = AppError {
         message: Some("Invalid time format".into()),
-        cause: None,
-        error_type: AppErrorType::InvalidTimeFormat,
+        cause: Some(format!("Unexpected time {:?}", time)),
+        error_type: AppErrorType::UpstreamBadDataError,
+        parameter: None,
     };
 
     let time = time.split(":").collect::<Vec<&str>>();
-    let Ok(hour) = time[0].parse::<u32>() else {
+    let Some(Ok(hour)) = time.first().map(|hour| hour.parse::<u32>()) else {
         return Err(err);
     };
-    let Ok(min) = time[1].parse::<u32>() else {
+    let Some(Ok(min)) = time.get(1).map(|min| min.parse::<u32>()) else {
         return Err(err);
     };
-    let Ok(sec) = time[2].parse::<u32>() else {
+    let Some(Ok(sec)) = time.get(2).map(|sec| sec.parse::<u32>()) else {
         return Err(err);
     };
     NaiveTime::from_hms_opt(hour, min, sec).ok_or(err)
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{Number, Value};
+
+    use super::*;
+
+    /// Field names upstream uses, mixed with random ones, so generated
+    /// objects regularly look like real records.
+    fn field_name() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("train_id".to_string()),
+            Just("route_name".to_string()),
+            Just("time_est".to_string()),
+            Just("station_id".to_string()),
+            Just("fare".to_string()),
+            Just("distance".to_string()),
+            "[a-z_]{1,10}",
+        ]
+    }
+
+    fn json_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::Bool),
+            any::<i64>().prop_map(Value::from),
+            any::<f64>().prop_map(|n| Number::from_f64(n).map_or(Value::Null, Value::Number)),
+            time_string().prop_map(Value::String),
+            ".*".prop_map(Value::String),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Value::Array),
+                prop::collection::vec((field_name(), inner), 0..8)
+                    .prop_map(|fields| Value::Object(fields.into_iter().collect())),
+            ]
+        })
+    }
+
+    fn time_string() -> impl Strategy<Value = String> {
+        prop_oneof!["[0-9]{0,3}(:[0-9]{0,3}){0,3}", "[0-9:]{0,8}", ".{0,8}"]
+    }
+
+    /// Objects holding every field a DTO needs, with fuzzed values, so most
+    /// rows get past deserialization and into the conversion behind it.
+    fn upstream_row() -> impl Strategy<Value = Value> {
+        let field = prop_oneof![
+            (0u32..30, 0u32..70, prop::option::of(0u32..70)).prop_map(|(hour, min, sec)| {
+                let sec = sec.map(|sec| format!(":{:02}", sec)).unwrap_or_default();
+                Value::String(format!("{:02}:{:02}{}", hour, min, sec))
+            }),
+            time_string().prop_map(Value::String),
+            "[A-Z0-9 ]{0,6}".prop_map(Value::String),
+            any::<u16>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            json_value(),
+        ];
+        let fields = [
+            "train_id",
+            "route_name",
+            "time_est",
+            "station_id",
+            "fare",
+            "distance",
+        ];
+        (
+            prop::collection::vec(field, fields.len()),
+            prop::collection::vec((field_name(), json_value()), 0..3),
+        )
+            .prop_map(move |(values, extra)| {
+                let mut row: serde_json::Map<String, Value> = extra.into_iter().collect();
+                for (name, value) in fields.iter().zip(values) {
+                    row.insert(name.to_string(), value);
+                }
+                Value::Object(row)
+            })
+    }
+
+    #[test]
+    fn bad_rows_are_skipped_and_unknown_stations_patched() {
+        let rows = serde_json::json!([
+            {"train_id": "1722", "station_id": "BOO", "time_est": "05:00:00"},
+            {"train_id": 1722, "station_id": "XYZ", "time_est": "05:10:00"},
+            {"train_id": "1722", "station_id": "CLT", "time_est": "5 past 5"},
+            {"train_id": "1722", "time_est": "05:20:00"},
+            {"train_id": "1722", "station_id": "MRI", "time_est": "06:00:00"},
+        ]);
+        let Value::Array(rows) = rows else {
+            unreachable!()
+        };
+        let (schedules, warnings) = TrainSchedule::from_rows(rows);
+
+        let stations = schedules
+            .iter()
+            .map(|schedule| schedule.station)
+            .collect::<Vec<_>>();
+        assert_eq!(stations, [Station::BOO, Station::Unknown, Station::MRI]);
+        assert_eq!(schedules[1].train_id, "1722");
+        assert_eq!(schedules[1].station_id(), "XYZ");
+        let reported = warnings
+            .iter()
+            .map(|warning| (warning.row, warning.skipped))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reported,
+            [(Some(1), false), (Some(2), true), (Some(3), true)]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn upstream_json_never_panics(
+            rows in prop::collection::vec(upstream_row(), 0..16),
+        ) {
+            let _ = StationSchedule::from_rows(rows.clone());
+            let _ = TrainSchedule::from_rows(rows.clone());
+            let (route_info, _) = RouteInfoDTO::from_rows(rows);
+            for dto in route_info {
+                let _ = Fare::from(dto.clone());
+                let _ = Distance::from(dto);
+            }
+        }
+
+        #[test]
+        fn every_upstream_row_is_kept_or_reported(
+            rows in prop::collection::vec(json_value(), 0..16),
+        ) {
+            let count = rows.len();
+            let (schedules, warnings) = StationSchedule::from_rows(rows.clone());
+            let skipped = warnings.iter().filter(|warning| warning.skipped).count();
+            prop_assert_eq!(schedules.len() + skipped, count);
+
+            let (schedules, warnings) = TrainSchedule::from_rows(rows.clone());
+            let skipped = warnings.iter().filter(|warning| warning.skipped).count();
+            prop_assert_eq!(schedules.len() + skipped, count);
+
+            let (route_info, warnings) = RouteInfoDTO::from_rows(rows);
+            prop_assert_eq!(route_info.len() + warnings.len(), count);
+        }
+
+        #[test]
+        fn time_parsing_never_panics(time in time_string()) {
+            if let Ok(parsed) = to_naive_time_hm(time.clone()) {
+                prop_assert_eq!(parsed.format("%S").to_string(), "00");
+            }
+            let _ = to_naive_time_hms(time);
+        }
+
+        #[test]
+        fn station_schedule_keeps_upstream_values(
+            train_id in ".+",
+            route_name in ".*",
+            hour in 0u32..24,
+            min in 0u32..60,
+            seconds in "(:[0-5][0-9])?",
+        ) {
+            let schedule = StationSchedule::from_dto(StationScheduleDTO {
+                train_id: train_id.clone(),
+                route_name: route_name.clone(),
+                time_est: format!("{:02}:{:02}{}", hour, min, seconds),
+            })
+            .unwrap();
+            prop_assert_eq!(schedule.train_id, train_id);
+            prop_assert_eq!(schedule.route_name, route_name);
+            prop_assert_eq!(schedule.time_est, NaiveTime::from_hms_opt(hour, min, 0).unwrap());
+        }
+
+        #[test]
+        fn out_of_range_times_are_rejected(hour in 24u32..1000, min in 0u32..1000) {
+            let bad_hour = format!("{}:{:02}", hour, min % 60);
+            let bad_minute = format!("{:02}:{}", min % 24, min + 60);
+            prop_assert!(to_naive_time_hm(bad_hour).is_err());
+            prop_assert!(to_naive_time_hm(bad_minute).is_err());
+        }
+
+        #[test]
+        fn unknown_stations_become_placeholders(station_id in "[A-Z]{1,4}|.{0,6}") {
+            let schedule = TrainSchedule::from_dto(TrainScheduleDTO {
+                train_id: "1234".into(),
+                station_id: station_id.clone(),
+                time_est: "05:30:00".into(),
+            })
+            .unwrap();
+            let known = Station::from_str(&station_id).is_ok();
+            prop_assert_eq!(schedule.station == Station::Unknown, !known);
+            prop_assert_eq!(schedule.station_id(), station_id);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use actix_web::{get, HttpResponse};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+use crate::{
+    analytics::{DestinationService, HeadwayAnalytics, HeadwayStats, HourlyFrequency},
+    batch::{BatchQuery, BatchResult},
+    error::AppErrorResponse,
+    format::OutputFormat,
+    line::TrainLine,
+    model::{
+        DataWarning, Distance, Fare, FastestRoute, FirstLastTrain, Journey, Leg, LegMode,
+        RankedJourney, RouteAlternatives, RouteProfile, RouteTag, SkippedPath, StationSchedule,
+        StationScheduleList, StopTime, TrainSchedule, TrainScheduleList, TransitRoutes,
+    },
+    pathfinder::RouteSort,
+    position::{LinePositions, TrainPosition, TrainStatus},
+    quality::DataQualityReport,
+    route,
+    station::Station,
+    subscription::{AlertRecord, Subscription, SubscriptionParam},
+    timetable::{RouteNameChange, StopTimeShift, TimetableChange, TrainRoute, TrainStop},
+    v1::{self, LineResource, StationResource, TrainResource},
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "KRL Service"),
+    paths(
+        route::station_schedule,
+        route::departures_stream,
+        route::train_schedule,
+        route::train_position,
+        route::train_positions,
+        route::train_fare,
+        route::distance,
+        route::get_fastest_route,
+        route::journey_ics,
+        route::routes,
+        route::get_route_profile,
+        route::first_last_train,
+        route::get_transit_route,
+        route::station_list,
+        route::line_list,
+        route::create_subscription,
+        route::subscription_list,
+        route::subscription_detail,
+        route::update_subscription,
+        route::delete_subscription,
+        route::history_dates,
+        route::history_station_schedule,
+        route::history_train_schedule,
+        route::timetable_changes,
+        route::headways,
+        route::batch_queries,
+        route::data_quality,
+        v1::list_stations,
+        v1::get_station,
+        v1::list_station_departures,
+        v1::get_train,
+        v1::get_train_position,
+        v1::list_lines,
+        v1::get_line,
+        v1::list_line_positions,
+        v1::get_fare,
+        v1::get_distance,
+        v1::list_journeys,
+        v1::list_all_journeys,
+        v1::get_journey_profile,
+        v1::get_first_last_journeys
+    ),
+    components(schemas(
+        AppErrorResponse,
+        BatchQuery,
+        BatchResult,
+        DataQualityReport,
+        DataWarning,
+        StationSchedule,
+        StationScheduleList,
+        TrainSchedule,
+        TrainScheduleList,
+        Fare,
+        Distance,
+        OutputFormat,
+        RouteSort,
+        FastestRoute,
+        FirstLastTrain,
+        RouteProfile,
+        TransitRoutes,
+        RouteAlternatives,
+        RankedJourney,
+        RouteTag,
+        Journey,
+        Leg,
+        LegMode,
+        StopTime,
+        SkippedPath,
+        TrainPosition,
+        LinePositions,
+        TrainStatus,
+        Station,
+        TrainLine,
+        Subscription,
+        SubscriptionParam,
+        AlertRecord,
+        TimetableChange,
+        TrainRoute,
+        RouteNameChange,
+        StopTimeShift,
+        TrainStop,
+        HeadwayAnalytics,
+        HeadwayStats,
+        HourlyFrequency,
+        DestinationService,
+        StationResource,
+        LineResource,
+        TrainResource
+    ))
+)]
+pub struct ApiDoc;
+
+#[get("/openapi.json")]
+async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Swagger UI bundled into the binary, reading the document served at
+/// `/openapi.json`.
+pub fn swagger_ui() -> SwaggerUi {
+    SwaggerUi::new("/docs/{_:.*}").config(Config::from("/openapi.json"))
+}
@@ -1,12 +1,21 @@
-use std::collections::HashSet;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
 
 use chrono::{Duration, NaiveDateTime, NaiveTime};
+use futures::{stream, StreamExt};
+use serde_derive::Deserialize;
+use utoipa::ToSchema;
 
 use crate::{
     error::{AppError, AppErrorType},
-    fetch::{fetch_station_schedule, fetch_train_schedule},
+    fetch::{fetch_fare, fetch_station_schedule, fetch_train_schedule, Upstream},
     line::{NeighbouringLine, TrainLine},
-    model::TrainSchedule,
+    model::{
+        service_time, Journey, Leg, LegMode, RankedJourney, RouteTag, SkippedPath, TrainSchedule,
+        SERVICE_DAY_START,
+    },
     station::Station,
 };
 
@@ -16,15 +25,16 @@ fn dfs(
     path: &mut Vec<NeighbouringLine>,
     mut paths: Vec<Vec<NeighbouringLine>>,
     on_path: &mut HashSet<TrainLine>,
+    avoid_lines: &[TrainLine],
 ) -> Vec<Vec<NeighbouringLine>> {
     path.push(from.clone());
-    on_path.insert(from.line.clone());
+    on_path.insert(from.line);
     if from.line == to {
         paths.push(path.clone());
     } else {
         for neighbour in from.line.neighbour() {
-            if !on_path.contains(&neighbour.line) {
-                paths = dfs(neighbour, to, path, paths, on_path);
+            if !on_path.contains(&neighbour.line) && !avoid_lines.contains(&neighbour.line) {
+                paths = dfs(neighbour, to, path, paths, on_path, avoid_lines);
             }
         }
     }
@@ -34,22 +44,138 @@ fn dfs(
     paths
 }
 
-fn generate_all_transit_paths(from: Station, to: Station) -> Vec<Vec<Station>> {
+/// Constraints applied on top of the plain line graph search.
+///
+/// `via` stations are visited in order, with a change of train at each of them.
+/// `avoid_stations` are never called at, not even by a train passing through,
+/// and trains running on `avoid_lines` are never ridden.
+#[derive(Debug, Clone, Default)]
+pub struct RouteConstraint {
+    pub via: Vec<Station>,
+    pub avoid_stations: Vec<Station>,
+    pub avoid_lines: Vec<TrainLine>,
+}
+
+impl RouteConstraint {
+    pub fn is_empty(&self) -> bool {
+        self.via.is_empty() && self.avoid_stations.is_empty() && self.avoid_lines.is_empty()
+    }
+
+    /// Whether riding a train of `line` over `stops` keeps to the constraint.
+    fn allows_ride(&self, line: Option<TrainLine>, stops: &[TrainSchedule]) -> bool {
+        line.is_none_or(|line| !self.avoid_lines.contains(&line))
+            && stops
+                .iter()
+                .all(|stop| !self.avoid_stations.contains(&stop.station))
+    }
+
+    fn validate(&self, from: Station, to: Station) -> Result<(), AppError> {
+        let invalid = |message: String, parameter: &str| AppError {
+            message: Some(message),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some(parameter.into()),
+        };
+
+        for station in [from, to] {
+            if self.avoid_stations.contains(&station) {
+                return Err(invalid(
+                    format!(
+                        "Station {}({}) cannot be both an endpoint and avoided",
+                        station.name(),
+                        station.id()
+                    ),
+                    "avoid-stations",
+                ));
+            }
+            if station
+                .line()
+                .iter()
+                .all(|line| self.avoid_lines.contains(line))
+            {
+                return Err(invalid(
+                    format!(
+                        "Every line serving {}({}) is avoided",
+                        station.name(),
+                        station.id()
+                    ),
+                    "avoid-lines",
+                ));
+            }
+        }
+        let mut previous = from;
+        for via in self.via.iter() {
+            if self.avoid_stations.contains(via) {
+                return Err(invalid(
+                    format!(
+                        "Station {}({}) cannot be both via and avoided",
+                        via.name(),
+                        via.id()
+                    ),
+                    "via",
+                ));
+            }
+            if *via == previous || *via == to {
+                return Err(invalid(
+                    format!(
+                        "Via station {}({}) cannot repeat the previous stop or station to",
+                        via.name(),
+                        via.id()
+                    ),
+                    "via",
+                ));
+            }
+            if via
+                .line()
+                .iter()
+                .all(|line| self.avoid_lines.contains(line))
+            {
+                return Err(invalid(
+                    format!(
+                        "Every line serving via station {}({}) is avoided",
+                        via.name(),
+                        via.id()
+                    ),
+                    "avoid-lines",
+                ));
+            }
+            previous = *via;
+        }
+        Ok(())
+    }
+}
+
+fn generate_rail_transit_paths(
+    from: Station,
+    to: Station,
+    avoid_lines: &[TrainLine],
+) -> Vec<Vec<Station>> {
     let mut station_pathss = vec![];
     for from_line in from.line() {
+        if avoid_lines.contains(&from_line) {
+            continue;
+        }
         for to_line in to.line() {
+            if avoid_lines.contains(&to_line) {
+                continue;
+            }
             let station_paths = dfs(
                 NeighbouringLine {
-                    line: from_line.clone(),
+                    line: from_line,
                     transit_station: from,
                 },
-                to_line.clone(),
+                to_line,
                 &mut vec![],
                 vec![],
                 &mut HashSet::new(),
+                avoid_lines,
             )
             .into_iter()
-            .filter(|station_path| station_path.last().unwrap().transit_station != to)
+            .filter(|station_path| {
+                station_path
+                    .last()
+                    .is_some_and(|last| last.transit_station != to)
+            })
             .filter(|station_path| {
                 let mut iter = station_path.iter();
                 let first = iter.next();
@@ -75,133 +201,1163 @@ fn generate_all_transit_paths(from: Station, to: Station) -> Vec<Vec<Station>> {
     station_pathss
 }
 
+/// Rail paths between the two stations, plus those that start by walking away
+/// from `from` or end by walking into `to`. Footpaths only ever join stations
+/// on the same line, so they are not part of the line graph itself.
+fn generate_all_transit_paths(
+    from: Station,
+    to: Station,
+    avoid_lines: &[TrainLine],
+) -> Vec<Vec<Station>> {
+    let mut station_paths = generate_rail_transit_paths(from, to, avoid_lines);
+    if from.footpath_to(&to).is_some() {
+        station_paths.push(vec![from, to]);
+    }
+    for footpath in from.footpath() {
+        if footpath.station == to {
+            continue;
+        }
+        for rail_path in generate_rail_transit_paths(footpath.station, to, avoid_lines) {
+            let mut station_path = vec![from];
+            station_path.extend(rail_path);
+            station_paths.push(station_path);
+        }
+    }
+    for footpath in to.footpath() {
+        if footpath.station == from {
+            continue;
+        }
+        for mut station_path in generate_rail_transit_paths(from, footpath.station, avoid_lines) {
+            station_path.push(to);
+            station_paths.push(station_path);
+        }
+    }
+    station_paths.retain(|station_path| {
+        station_path
+            .iter()
+            .enumerate()
+            .all(|(i, station)| !station_path[..i].contains(station))
+    });
+    station_paths
+}
+
+fn generate_constrained_transit_paths(
+    from: Station,
+    to: Station,
+    constraint: &RouteConstraint,
+) -> Vec<Vec<Station>> {
+    let mut waypoints = vec![from];
+    waypoints.extend(constraint.via.iter().copied());
+    waypoints.push(to);
+
+    let mut station_paths = vec![vec![from]];
+    for waypoint in waypoints.windows(2) {
+        let segments =
+            generate_all_transit_paths(waypoint[0], waypoint[1], &constraint.avoid_lines);
+        station_paths = station_paths
+            .iter()
+            .flat_map(|station_path| {
+                segments.iter().map(move |segment| {
+                    let mut station_path = station_path.clone();
+                    station_path.extend(segment.iter().skip(1));
+                    station_path
+                })
+            })
+            .collect();
+    }
+
+    let mut constrained_paths: Vec<Vec<Station>> = vec![];
+    for station_path in station_paths {
+        let avoided = station_path
+            .iter()
+            .any(|station| constraint.avoid_stations.contains(station));
+        if !avoided && !constrained_paths.contains(&station_path) {
+            constrained_paths.push(station_path);
+        }
+    }
+    constrained_paths
+}
+
 pub fn generate_all_transit_routes(
     from: Station,
     to: Station,
+    constraint: &RouteConstraint,
 ) -> Result<Vec<Vec<Station>>, AppError> {
     if from == to {
-        Err(AppError {
+        return Err(AppError {
             message: Some("Station from and station cannot be the same".into()),
             cause: None,
             error_type: AppErrorType::InvalidRequestParameter,
-        })
-    } else {
-        Ok(generate_all_transit_paths(from, to))
+            parameter: Some("station-to".into()),
+        });
     }
+    constraint.validate(from, to)?;
+
+    let paths = generate_constrained_transit_paths(from, to, constraint);
+    if paths.is_empty() && !constraint.is_empty() {
+        return Err(AppError {
+            message: Some(format!(
+                "No route from {}({}) to {}({}) satisfies the given constraints",
+                from.name(),
+                from.id(),
+                to.name(),
+                to.id()
+            )),
+            cause: None,
+            error_type: AppErrorType::NoRouteError,
+            parameter: None,
+        });
+    }
+    Ok(paths)
+}
+
+/// Every way to ride a train from `from` to `to`, as indices into its stops:
+/// each call at `from` at or after `earliest`, paired with the next call at
+/// `to`. Stops are in running order, so trains heading the other way never
+/// match, while loop services calling at `from` twice give two candidates.
+fn leg_candidates(
+    stops: &[TrainSchedule],
+    from: Station,
+    to: Station,
+    earliest: NaiveTime,
+) -> Vec<(usize, usize)> {
+    stops
+        .iter()
+        .enumerate()
+        .filter(|(_, stop)| stop.station == from && stop.time_est >= earliest)
+        .filter_map(|(boarding, _)| {
+            let alight = (boarding + 1..stops.len()).find(|i| stops[*i].station == to)?;
+            Some((boarding, alight))
+        })
+        .collect()
+}
+
+/// The ride from `from` to `to` leaving no earlier than `earliest` that
+/// reaches `to` first. Of rides arriving together, the one boarding last is
+/// taken so the rider does not sit through a loop.
+fn match_leg(
+    stops: &[TrainSchedule],
+    from: Station,
+    to: Station,
+    earliest: NaiveTime,
+) -> Option<(usize, usize)> {
+    leg_candidates(stops, from, to, earliest)
+        .into_iter()
+        .min_by_key(|(boarding, alight)| {
+            (stops[*alight].time_est, Reverse(stops[*boarding].time_est))
+        })
+}
+
+/// Mirror of [`match_leg`]: the ride reaching `to` by `latest` that boards
+/// last, arriving first among rides boarding together. Times compare within
+/// the service day, so a ride past midnight counts as the latest.
+fn match_last_leg(
+    stops: &[TrainSchedule],
+    from: Station,
+    to: Station,
+    latest: NaiveTime,
+) -> Option<(usize, usize)> {
+    leg_candidates(stops, from, to, NaiveTime::MIN)
+        .into_iter()
+        .filter(|(_, alight)| service_time(stops[*alight].time_est) <= service_time(latest))
+        .max_by_key(|(boarding, alight)| {
+            (
+                service_time(stops[*boarding].time_est),
+                Reverse(service_time(stops[*alight].time_est)),
+            )
+        })
 }
 
 async fn get_first_train_schedule_to_station_same_line(
+    upstream: &Upstream,
     from: Station,
     to: Station,
     time_start: NaiveTime,
-) -> Result<Vec<TrainSchedule>, AppError> {
-    let first = fetch_station_schedule(from, time_start, NaiveDateTime::MAX.time()).await?;
+    arriving: Option<&Leg>,
+    constraint: &RouteConstraint,
+) -> Result<Leg, AppError> {
+    // When changing trains, how long the change takes depends on the line of
+    // the train being boarded, so it is only known per candidate.
+    let ready = |line| match arriving {
+        Some(arriving) => time_start + from.min_transfer_time(arriving.line, line),
+        None => time_start,
+    };
+    let first =
+        fetch_station_schedule(upstream, from, time_start, NaiveDateTime::MAX.time()).await?;
+    // Trains are looked up one at a time in departure order. A later one may
+    // still arrive first, so the search only ends once trains leave after the
+    // best arrival; those are never fetched.
+    let mut best: Option<Leg> = None;
     for train in first {
-        let train_schedules = fetch_train_schedule(&train.train_id).await?;
-        let mut start_index = None;
-        let mut stop_index = None;
-        for (i, schedule) in train_schedules.iter().enumerate() {
-            if schedule.station == from {
-                start_index = Some(i);
-            }
-            if schedule.station == to {
-                stop_index = Some(i + 1);
-                break;
-            }
+        if best
+            .as_ref()
+            .is_some_and(|best| train.time_est >= best.alight.time)
+        {
+            break;
         }
-
-        if let (Some(start_index), Some(stop_index)) = (start_index, stop_index) {
-            if start_index < stop_index {
-                return Ok(train_schedules[start_index..stop_index].to_vec());
-            }
+        let train_schedules = fetch_train_schedule(upstream, &train.train_id).await?;
+        let line = TrainLine::of_run(train_schedules.iter().map(|stop| stop.station));
+        let Some((boarding, alight)) = match_leg(&train_schedules, from, to, ready(line)) else {
+            continue;
+        };
+        if !constraint.allows_ride(line, &train_schedules[boarding..=alight]) {
+            continue;
+        }
+        let Some(leg) = Leg::train(
+            train.route_name,
+            line,
+            train_schedules[boarding..=alight].to_vec(),
+        ) else {
+            continue;
+        };
+        let better = best.as_ref().is_none_or(|best| {
+            (leg.alight.time, Reverse(leg.board.time))
+                < (best.alight.time, Reverse(best.board.time))
+        });
+        if better {
+            best = Some(leg);
         }
     }
-    Err(AppError {
-        message: None,
-        cause: Some(format!(
+    best.ok_or_else(|| AppError {
+        message: Some(format!(
             "No train found from {}({}) to {}({})",
             from.name(),
             from.id(),
             to.name(),
             to.id()
         )),
-        error_type: crate::error::AppErrorType::NotFoundError,
+        cause: None,
+        error_type: AppErrorType::NoRouteError,
+        parameter: None,
     })
 }
 
 async fn concat_train_schedule_path_from_station_path(
+    upstream: &Upstream,
     path: Vec<Station>,
     time_start: NaiveTime,
     transit_duration: Duration,
-) -> Result<Vec<TrainSchedule>, AppError> {
-    let mut train_schedule_path = Vec::new();
+    constraint: &RouteConstraint,
+) -> Result<Vec<Leg>, AppError> {
+    let mut legs = Vec::new();
     let mut from_time = time_start;
 
-    // println!("path: {:#?}", path);
-    for (i, station) in path.iter().enumerate() {
-        if i == path.len() - 1 {
+    for pair in path.windows(2) {
+        let (station, next_station) = (pair[0], pair[1]);
+        if let Some(footpath) = station.footpath_to(&next_station) {
+            legs.push(Leg::walk(
+                station,
+                next_station,
+                from_time,
+                from_time + footpath.duration,
+            ));
+            from_time += footpath.duration;
+            continue;
+        }
+        let arriving = legs.last().filter(|leg| leg.mode == LegMode::Train);
+        let leg = get_first_train_schedule_to_station_same_line(
+            upstream,
+            station,
+            next_station,
+            from_time,
+            arriving,
+            constraint,
+        )
+        .await?;
+        from_time = leg.alight.time + transit_duration;
+        legs.push(leg);
+    }
+    retime_leading_walks(&mut legs);
+    Ok(legs)
+}
+
+/// Mirror of `get_first_train_schedule_to_station_same_line`: the train
+/// leaving `from` latest that still reaches `to` by `arrive_by`.
+async fn get_last_train_schedule_to_station_same_line(
+    upstream: &Upstream,
+    from: Station,
+    to: Station,
+    arrive_by: NaiveTime,
+    departing: Option<&Leg>,
+    constraint: &RouteConstraint,
+) -> Result<Leg, AppError> {
+    let latest_arrival = |line| match departing {
+        Some(departing) => arrive_by - to.min_transfer_time(line, departing.line),
+        None => arrive_by,
+    };
+    // Past midnight, every listing of the evening before is still earlier
+    // in the service day.
+    let listed_until = if arrive_by < SERVICE_DAY_START {
+        NaiveDateTime::MAX.time()
+    } else {
+        arrive_by
+    };
+    let mut last = fetch_station_schedule(upstream, from, NaiveTime::MIN, listed_until).await?;
+    // Latest listing first; a train listed before the best boarding found
+    // cannot board later, since its later calls are listed on their own.
+    last.sort_by_key(|train| Reverse(service_time(train.time_est)));
+    let mut best: Option<Leg> = None;
+    for train in last {
+        if best
+            .as_ref()
+            .is_some_and(|best| service_time(train.time_est) < service_time(best.board.time))
+        {
             break;
         }
-        let next_station = path[i + 1].clone();
-        let train_schedule =
-            get_first_train_schedule_to_station_same_line(station.clone(), next_station, from_time)
-                .await?;
-        // println!("train_schedule: {:#?}", train_schedule);
-        from_time = train_schedule.last().unwrap().time_est + transit_duration;
-        train_schedule_path.extend(train_schedule);
+        let train_schedules = fetch_train_schedule(upstream, &train.train_id).await?;
+        let line = TrainLine::of_run(train_schedules.iter().map(|stop| stop.station));
+        let Some((boarding, alight)) =
+            match_last_leg(&train_schedules, from, to, latest_arrival(line))
+        else {
+            continue;
+        };
+        if !constraint.allows_ride(line, &train_schedules[boarding..=alight]) {
+            continue;
+        }
+        let Some(leg) = Leg::train(
+            train.route_name,
+            line,
+            train_schedules[boarding..=alight].to_vec(),
+        ) else {
+            continue;
+        };
+        let order = |leg: &Leg| {
+            (
+                service_time(leg.board.time),
+                Reverse(service_time(leg.alight.time)),
+            )
+        };
+        let better = best.as_ref().is_none_or(|best| order(&leg) > order(best));
+        if better {
+            best = Some(leg);
+        }
+    }
+    best.ok_or_else(|| AppError {
+        message: Some(format!(
+            "No train found from {}({}) to {}({}) arriving by {}",
+            from.name(),
+            from.id(),
+            to.name(),
+            to.id(),
+            arrive_by.format("%H:%M")
+        )),
+        cause: None,
+        error_type: AppErrorType::NoRouteError,
+        parameter: None,
+    })
+}
+
+/// Backward search along a station path: picks the last train into the
+/// destination, then the last train that still makes each connection before
+/// it, leaving the same transfer margins as the forward search.
+async fn concat_last_train_schedule_path_from_station_path(
+    upstream: &Upstream,
+    path: Vec<Station>,
+    arrive_by: NaiveTime,
+    transit_duration: Duration,
+    constraint: &RouteConstraint,
+) -> Result<Vec<Leg>, AppError> {
+    let mut legs = Vec::new();
+    let mut deadline = arrive_by;
+
+    for pair in path.windows(2).rev() {
+        let (station, next_station) = (pair[0], pair[1]);
+        if let Some(footpath) = station.footpath_to(&next_station) {
+            legs.push(Leg::walk(
+                station,
+                next_station,
+                deadline - footpath.duration,
+                deadline,
+            ));
+            deadline -= footpath.duration;
+            continue;
+        }
+        if !legs.is_empty() {
+            deadline -= transit_duration;
+        }
+        let departing = legs.last().filter(|leg| leg.mode == LegMode::Train);
+        let leg = get_last_train_schedule_to_station_same_line(
+            upstream,
+            station,
+            next_station,
+            deadline,
+            departing,
+            constraint,
+        )
+        .await?;
+        deadline = leg.board.time;
+        legs.push(leg);
     }
-    Ok(train_schedule_path)
+    legs.reverse();
+    retime_trailing_walks(&mut legs);
+    Ok(legs)
 }
 
-async fn generate_and_concat_train_schedule_path(
+/// Walks after the last train are shifted to start as soon as that train
+/// arrives, so that a journey is not charged for waiting at the destination.
+fn retime_trailing_walks(legs: &mut [Leg]) {
+    let Some(last_train) = legs.iter().rposition(|leg| leg.mode == LegMode::Train) else {
+        return;
+    };
+    let mut arrival = legs[last_train].alight.time;
+    for leg in legs[last_train + 1..].iter_mut() {
+        let duration = leg.alight.time - leg.board.time;
+        leg.board.time = arrival;
+        leg.alight.time = arrival + duration;
+        arrival = leg.alight.time;
+    }
+}
+
+/// Walks before the first train are shifted to end just as that train leaves,
+/// so that a journey is not charged for waiting at the origin.
+fn retime_leading_walks(legs: &mut [Leg]) {
+    let Some(first_train) = legs.iter().position(|leg| leg.mode == LegMode::Train) else {
+        return;
+    };
+    let mut boarding = legs[first_train].board.time;
+    for leg in legs[..first_train].iter_mut().rev() {
+        let duration = leg.alight.time - leg.board.time;
+        leg.alight.time = boarding;
+        leg.board.time = boarding - duration;
+        boarding = leg.board.time;
+    }
+}
+
+fn skipped_path(path: Vec<Station>, err: &AppError) -> SkippedPath {
+    SkippedPath {
+        path,
+        reason: err.detail(),
+    }
+}
+
+/// Evaluates every candidate path concurrently. Paths that fail are reported
+/// back instead of failing the search, unless none of them succeed.
+pub async fn generate_and_concat_train_schedule_path(
+    upstream: &Upstream,
     station_from: Station,
     station_to: Station,
     time_start: NaiveTime,
     transit_duration: Duration,
-) -> Result<Vec<Vec<TrainSchedule>>, AppError> {
-    let paths = generate_all_transit_paths(station_from, station_to);
-    let mut train_schedule_path = Vec::new();
-    for path in paths.into_iter() {
-        let train_schedules =
-            concat_train_schedule_path_from_station_path(path, time_start, transit_duration).await?;
-        train_schedule_path.push(train_schedules);
+    constraint: &RouteConstraint,
+    concurrency: usize,
+) -> Result<(Vec<Journey>, Vec<SkippedPath>), AppError> {
+    let paths = generate_all_transit_routes(station_from, station_to, constraint)?;
+    let results = stream::iter(paths)
+        .map(|path| async move {
+            let legs = concat_train_schedule_path_from_station_path(
+                upstream,
+                path.clone(),
+                time_start,
+                transit_duration,
+                constraint,
+            )
+            .await;
+            (path, legs)
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut journeys = Vec::new();
+    let mut skipped_paths = Vec::new();
+    let mut first_err = None;
+    for (path, legs) in results {
+        match legs {
+            Ok(legs) => journeys.extend(Journey::from_legs(legs)),
+            Err(err) => {
+                skipped_paths.push(skipped_path(path, &err));
+                first_err.get_or_insert(err);
+            }
+        }
     }
-    Ok(train_schedule_path)
+    if let (true, Some(err)) = (journeys.is_empty(), first_err) {
+        return Err(err);
+    }
+    Ok((journeys, skipped_paths))
 }
 
 pub async fn choose_fastest_path(
+    upstream: &Upstream,
     station_from: Station,
     station_to: Station,
     time_start: NaiveTime,
     transit_duration: Duration,
-) -> Result<Vec<TrainSchedule>, AppError> {
-    let paths =
-        generate_and_concat_train_schedule_path(station_from, station_to, time_start, transit_duration).await?;
+    constraint: &RouteConstraint,
+    concurrency: usize,
+) -> Result<(Journey, Vec<SkippedPath>), AppError> {
+    let (journeys, skipped_paths) = generate_and_concat_train_schedule_path(
+        upstream,
+        station_from,
+        station_to,
+        time_start,
+        transit_duration,
+        constraint,
+        concurrency,
+    )
+    .await?;
     let mut fastest_path = None;
-    let mut fastest_time = chrono::Duration::max_value();
-    for path in paths.into_iter() {
-        if let Some(last_train_schedule) = path.last() {
-            if let Some(first_train_schedule) = path.first() {
-                let time_diff = last_train_schedule.time_est - first_train_schedule.time_est;
-                if time_diff < fastest_time {
-                    fastest_time = time_diff;
-                    fastest_path = Some(path);
-                }
-            }
+    let mut fastest_time = i64::MAX;
+    for journey in journeys.into_iter() {
+        if journey.total_duration < fastest_time {
+            fastest_time = journey.total_duration;
+            fastest_path = Some(journey);
         }
     }
     let Some(fastest_path) = fastest_path else {
         return Err(AppError {
-            message: None,
-            cause: Some(format!(
+            message: Some(format!(
                 "No path found from {}({}) to {}({})",
                 station_from.name(),
                 station_from.id(),
                 station_to.name(),
                 station_to.id()
             )),
-            error_type: crate::error::AppErrorType::NotFoundError,
+            cause: None,
+            error_type: AppErrorType::NoRouteError,
+            parameter: None,
         });
     };
-    Ok(fastest_path)
+    Ok((fastest_path, skipped_paths))
+}
+
+/// The earliest departure and the latest departure of the service day that
+/// complete the trip, over every candidate path. The day runs from
+/// [`SERVICE_DAY_START`], so last trains past midnight are included.
+pub async fn choose_first_last_paths(
+    upstream: &Upstream,
+    station_from: Station,
+    station_to: Station,
+    transit_duration: Duration,
+    concurrency: usize,
+) -> Result<(Journey, Journey, Vec<SkippedPath>), AppError> {
+    let (first_journeys, mut skipped_paths) = generate_and_concat_train_schedule_path(
+        upstream,
+        station_from,
+        station_to,
+        SERVICE_DAY_START,
+        transit_duration,
+        &RouteConstraint::default(),
+        concurrency,
+    )
+    .await?;
+
+    let paths = generate_all_transit_routes(station_from, station_to, &RouteConstraint::default())?;
+    let results = stream::iter(paths)
+        .map(|path| async move {
+            let legs = concat_last_train_schedule_path_from_station_path(
+                upstream,
+                path.clone(),
+                SERVICE_DAY_START - Duration::seconds(1),
+                transit_duration,
+                &RouteConstraint::default(),
+            )
+            .await;
+            (path, legs)
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let mut last_journeys = Vec::new();
+    let mut first_err = None;
+    for (path, legs) in results {
+        match legs {
+            Ok(legs) => last_journeys.extend(Journey::from_legs(legs)),
+            Err(err) => {
+                skipped_paths.push(skipped_path(path, &err));
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+
+    let first = first_journeys.into_iter().min_by_key(|journey| {
+        (
+            service_time(journey.departure),
+            service_time(journey.arrival),
+        )
+    });
+    let last = last_journeys.into_iter().max_by_key(|journey| {
+        (
+            service_time(journey.departure),
+            Reverse(service_time(journey.arrival)),
+        )
+    });
+    match (first, last, first_err) {
+        (Some(first), Some(last), _) => Ok((first, last, skipped_paths)),
+        (_, _, Some(err)) => Err(err),
+        _ => Err(AppError {
+            message: Some(format!(
+                "No path found from {}({}) to {}({})",
+                station_from.name(),
+                station_from.id(),
+                station_to.name(),
+                station_to.id()
+            )),
+            cause: None,
+            error_type: AppErrorType::NoRouteError,
+            parameter: None,
+        }),
+    }
+}
+
+/// Successive journeys along one station path, each leaving after the
+/// previous one, until `time_to` or `max_journeys` is reached.
+async fn profile_station_path(
+    upstream: &Upstream,
+    path: Vec<Station>,
+    time_from: NaiveTime,
+    time_to: NaiveTime,
+    transit_duration: Duration,
+    max_journeys: usize,
+    constraint: &RouteConstraint,
+) -> Result<Vec<Journey>, AppError> {
+    let mut journeys = Vec::new();
+    let mut departure_after = time_from;
+    while journeys.len() < max_journeys {
+        let legs = match concat_train_schedule_path_from_station_path(
+            upstream,
+            path.clone(),
+            departure_after,
+            transit_duration,
+            constraint,
+        )
+        .await
+        {
+            Ok(legs) => legs,
+            Err(AppError {
+                error_type: AppErrorType::NoRouteError,
+                ..
+            }) => break,
+            Err(err) => return Err(err),
+        };
+        let Some(journey) = Journey::from_legs(legs) else {
+            break;
+        };
+        if journey.departure < departure_after || journey.departure > time_to {
+            break;
+        }
+        if !journey.has_train() {
+            journeys.push(journey);
+            break;
+        }
+        departure_after = journey.departure + Duration::minutes(1);
+        let wrapped = departure_after <= journey.departure;
+        journeys.push(journey);
+        if wrapped {
+            break;
+        }
+    }
+    Ok(journeys)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn profile_paths(
+    upstream: &Upstream,
+    station_from: Station,
+    station_to: Station,
+    time_from: NaiveTime,
+    time_to: NaiveTime,
+    transit_duration: Duration,
+    constraint: &RouteConstraint,
+    concurrency: usize,
+) -> Result<(Vec<Journey>, Vec<SkippedPath>), AppError> {
+    if time_from > time_to {
+        return Err(AppError {
+            message: Some("Time from cannot be later than time to".into()),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("time-from".into()),
+        });
+    }
+    let paths = generate_all_transit_routes(station_from, station_to, constraint)?;
+    let results = stream::iter(paths)
+        .map(|path| async move {
+            let journeys = profile_station_path(
+                upstream,
+                path.clone(),
+                time_from,
+                time_to,
+                transit_duration,
+                usize::MAX,
+                constraint,
+            )
+            .await;
+            (path, journeys)
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut journeys = Vec::new();
+    let mut skipped_paths = Vec::new();
+    let mut first_err = None;
+    let mut succeeded = false;
+    for (path, path_journeys) in results {
+        match path_journeys {
+            Ok(path_journeys) => {
+                succeeded = true;
+                journeys.extend(path_journeys);
+            }
+            Err(err) => {
+                skipped_paths.push(skipped_path(path, &err));
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    if let (false, Some(err)) = (succeeded, first_err) {
+        return Err(err);
+    }
+
+    let mut profile: Vec<Journey> = Vec::new();
+    for journey in journeys.iter() {
+        let dominated = journeys.iter().any(|other| other.dominates(journey));
+        let duplicate = profile.iter().any(|kept| {
+            kept.departure == journey.departure
+                && kept.arrival == journey.arrival
+                && kept.transfers == journey.transfers
+        });
+        if !dominated && !duplicate {
+            profile.push(journey.clone());
+        }
+    }
+    profile.sort_by_key(|journey| (journey.departure, journey.arrival));
+    Ok((profile, skipped_paths))
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteSort {
+    #[default]
+    Arrival,
+    Duration,
+    Transfers,
+    Fare,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RankOption {
+    pub k: usize,
+    pub sort: RouteSort,
+}
+
+/// Where a journey is tapped in and out on each of its trips. KRL charges
+/// one fare per trip, however many trains it takes, but walking between
+/// stations means tapping out, so every walk between trains starts a new trip.
+fn fare_stations(journey: &Journey) -> Vec<(Station, Station)> {
+    journey
+        .legs
+        .split(|leg| leg.mode == LegMode::Walk)
+        .filter_map(|trains| {
+            let first = trains.first()?;
+            let last = trains.last()?;
+            Some((first.board.station, last.alight.station))
+        })
+        .collect()
+}
+
+fn journey_fare(
+    journey: &Journey,
+    fares: &HashMap<(Station, Station), Option<u16>>,
+) -> Option<u32> {
+    let trips = fare_stations(journey);
+    if trips.is_empty() {
+        return None;
+    }
+    trips
+        .iter()
+        .map(|trip| fares.get(trip).copied().flatten().map(u32::from))
+        .sum()
+}
+
+/// Fares of every distinct trip among `journeys`. Journeys between the same
+/// stations share a trip, so this is usually a single request.
+async fn fetch_journey_fares(
+    upstream: &Upstream,
+    journeys: &[Journey],
+    concurrency: usize,
+) -> HashMap<(Station, Station), Option<u16>> {
+    let pairs = journeys
+        .iter()
+        .flat_map(fare_stations)
+        .collect::<HashSet<_>>();
+
+    stream::iter(pairs)
+        .map(|(board, alight)| async move {
+            let fare = fetch_fare(upstream, board, alight)
+                .await
+                .ok()
+                .map(|fare| fare.fare);
+            ((board, alight), fare)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await
+}
+
+fn journey_key(journey: &Journey) -> Vec<(Station, Option<String>)> {
+    journey
+        .legs
+        .iter()
+        .map(|leg| (leg.board.station, leg.train_id.clone()))
+        .collect()
+}
+
+fn describe_difference(best: &RankedJourney, ranked: &RankedJourney) -> (Vec<RouteTag>, String) {
+    let mut tags = Vec::new();
+    let mut notes = Vec::new();
+
+    let duration = ranked.journey.total_duration - best.journey.total_duration;
+    if duration < 0 {
+        tags.push(RouteTag::Faster);
+        notes.push(format!("{} min faster", -duration));
+    } else if duration > 0 {
+        notes.push(format!("{} min slower", duration));
+    }
+    let arrival =
+        (service_time(ranked.journey.arrival) - service_time(best.journey.arrival)).num_minutes();
+    if arrival < 0 {
+        tags.push(RouteTag::EarlierArrival);
+        notes.push(format!("arrives {} min earlier", -arrival));
+    } else if arrival > 0 {
+        notes.push(format!("arrives {} min later", arrival));
+    }
+    let departure = (service_time(ranked.journey.departure) - service_time(best.journey.departure))
+        .num_minutes();
+    if departure > 0 {
+        tags.push(RouteTag::LaterDeparture);
+        notes.push(format!("leaves {} min later", departure));
+    }
+    match ranked.journey.transfers.cmp(&best.journey.transfers) {
+        std::cmp::Ordering::Less => {
+            tags.push(RouteTag::FewerTransfers);
+            notes.push(format!(
+                "{} fewer transfer(s)",
+                best.journey.transfers - ranked.journey.transfers
+            ));
+        }
+        std::cmp::Ordering::Greater => notes.push(format!(
+            "{} more transfer(s)",
+            ranked.journey.transfers - best.journey.transfers
+        )),
+        std::cmp::Ordering::Equal => {}
+    }
+    if let (Some(fare), Some(best_fare)) = (ranked.fare, best.fare) {
+        if fare < best_fare {
+            tags.push(RouteTag::Cheaper);
+            notes.push(format!("Rp{} cheaper", best_fare - fare));
+        } else if fare > best_fare {
+            notes.push(format!("Rp{} more expensive", fare - best_fare));
+        }
+    }
+
+    let summary = if notes.is_empty() {
+        "Same as the best option".to_string()
+    } else {
+        notes.join(", ")
+    };
+    (tags, summary)
+}
+
+/// The top `k` distinct journeys across every candidate path, ranked by the
+/// chosen criterion. Each one after the first is summarised against the first.
+#[allow(clippy::too_many_arguments)]
+pub async fn choose_k_paths(
+    upstream: &Upstream,
+    station_from: Station,
+    station_to: Station,
+    time_start: NaiveTime,
+    transit_duration: Duration,
+    constraint: &RouteConstraint,
+    rank: RankOption,
+    concurrency: usize,
+) -> Result<(Vec<RankedJourney>, Vec<SkippedPath>), AppError> {
+    if rank.k == 0 {
+        return Err(AppError {
+            message: Some("k must be at least 1".into()),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("k".into()),
+        });
+    }
+    let paths = generate_all_transit_routes(station_from, station_to, constraint)?;
+    let results = stream::iter(paths)
+        .map(|path| async move {
+            let journeys = profile_station_path(
+                upstream,
+                path.clone(),
+                time_start,
+                NaiveDateTime::MAX.time(),
+                transit_duration,
+                rank.k,
+                constraint,
+            )
+            .await;
+            (path, journeys)
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut journeys: Vec<Journey> = Vec::new();
+    let mut skipped_paths = Vec::new();
+    let mut first_err = None;
+    let mut succeeded = false;
+    for (path, path_journeys) in results {
+        match path_journeys {
+            Ok(path_journeys) => {
+                succeeded = true;
+                for journey in path_journeys {
+                    if journeys
+                        .iter()
+                        .all(|kept| journey_key(kept) != journey_key(&journey))
+                    {
+                        journeys.push(journey);
+                    }
+                }
+            }
+            Err(err) => {
+                skipped_paths.push(skipped_path(path, &err));
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    if let (false, Some(err)) = (succeeded, first_err) {
+        return Err(err);
+    }
+
+    let fares = fetch_journey_fares(upstream, &journeys, concurrency).await;
+    let mut ranked = Vec::new();
+    for journey in journeys {
+        let fare = journey_fare(&journey, &fares);
+        ranked.push(RankedJourney {
+            journey,
+            fare,
+            tags: vec![],
+            summary: String::new(),
+        });
+    }
+    ranked.sort_by(|a, b| {
+        let (x, y) = (&a.journey, &b.journey);
+        let by_arrival = x.arrival.cmp(&y.arrival);
+        match rank.sort {
+            RouteSort::Arrival => by_arrival.then(x.total_duration.cmp(&y.total_duration)),
+            RouteSort::Duration => x.total_duration.cmp(&y.total_duration).then(by_arrival),
+            RouteSort::Transfers => x.transfers.cmp(&y.transfers).then(by_arrival),
+            RouteSort::Fare => {
+                let fare = |ranked: &RankedJourney| ranked.fare.unwrap_or(u32::MAX);
+                fare(a).cmp(&fare(b)).then(by_arrival)
+            }
+        }
+    });
+    ranked.truncate(rank.k);
+
+    if ranked.is_empty() {
+        return Err(AppError {
+            message: Some(format!(
+                "No path found from {}({}) to {}({})",
+                station_from.name(),
+                station_from.id(),
+                station_to.name(),
+                station_to.id()
+            )),
+            cause: None,
+            error_type: AppErrorType::NoRouteError,
+            parameter: None,
+        });
+    }
+    ranked[0].summary = "Best option".to_string();
+    for i in 1..ranked.len() {
+        let (tags, summary) = describe_difference(&ranked[0], &ranked[i]);
+        ranked[i].tags = tags;
+        ranked[i].summary = summary;
+    }
+    Ok((ranked, skipped_paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{run, time};
+
+    #[test]
+    fn match_leg_follows_running_order() {
+        let stops = run(&[
+            (Station::BOO, "05:00"),
+            (Station::DP, "05:30"),
+            (Station::MRI, "06:00"),
+            (Station::JAKK, "06:20"),
+        ]);
+        assert_eq!(
+            match_leg(&stops, Station::DP, Station::JAKK, time("05:00")),
+            Some((1, 3))
+        );
+        assert_eq!(
+            match_leg(&stops, Station::JAKK, Station::DP, time("05:00")),
+            None
+        );
+    }
+
+    #[test]
+    fn match_leg_skips_calls_before_earliest() {
+        let stops = run(&[
+            (Station::BOO, "05:00"),
+            (Station::DP, "05:30"),
+            (Station::MRI, "06:00"),
+        ]);
+        assert_eq!(
+            match_leg(&stops, Station::DP, Station::MRI, time("05:31")),
+            None
+        );
+    }
+
+    #[test]
+    fn match_leg_boards_loop_service_at_last_call_before_arrival() {
+        let stops = run(&[
+            (Station::KPB, "06:00"),
+            (Station::JAKK, "06:10"),
+            (Station::KPB, "06:20"),
+            (Station::RJW, "06:25"),
+        ]);
+        assert_eq!(
+            match_leg(&stops, Station::KPB, Station::RJW, time("05:00")),
+            Some((2, 3))
+        );
+    }
+
+    #[test]
+    fn match_leg_prefers_earliest_arrival() {
+        // Loop service reaching MRI twice: once right after the first call at
+        // THB and again after a second round.
+        let stops = run(&[
+            (Station::THB, "06:00"),
+            (Station::MRI, "06:15"),
+            (Station::DU, "06:40"),
+            (Station::THB, "06:50"),
+            (Station::MRI, "07:05"),
+        ]);
+        assert_eq!(
+            match_leg(&stops, Station::THB, Station::MRI, time("05:00")),
+            Some((0, 1))
+        );
+        assert_eq!(
+            match_leg(&stops, Station::THB, Station::MRI, time("06:01")),
+            Some((3, 4))
+        );
+    }
+
+    #[test]
+    fn match_last_leg_boards_latest_arriving_in_time() {
+        let stops = run(&[
+            (Station::THB, "06:00"),
+            (Station::MRI, "06:15"),
+            (Station::DU, "06:40"),
+            (Station::THB, "06:50"),
+            (Station::MRI, "07:05"),
+        ]);
+        assert_eq!(
+            match_last_leg(&stops, Station::THB, Station::MRI, time("07:05")),
+            Some((3, 4))
+        );
+        assert_eq!(
+            match_last_leg(&stops, Station::THB, Station::MRI, time("07:00")),
+            Some((0, 1))
+        );
+    }
+
+    #[test]
+    fn match_last_leg_takes_rides_past_midnight() {
+        let stops = run(&[
+            (Station::THB, "23:30"),
+            (Station::MRI, "23:45"),
+            (Station::DU, "23:55"),
+            (Station::THB, "23:58"),
+            (Station::MRI, "00:12"),
+        ]);
+        let end_of_service = SERVICE_DAY_START - Duration::seconds(1);
+        assert_eq!(
+            match_last_leg(&stops, Station::THB, Station::MRI, end_of_service),
+            Some((3, 4))
+        );
+        assert_eq!(
+            match_last_leg(&stops, Station::THB, Station::MRI, time("00:05")),
+            Some((0, 1))
+        );
+        assert_eq!(
+            match_last_leg(&stops, Station::THB, Station::MRI, time("23:59")),
+            Some((0, 1))
+        );
+    }
+
+    #[test]
+    fn fare_covers_the_whole_trip() {
+        let train =
+            |stops: &[(Station, &str)]| Leg::train("route".into(), None, run(stops)).unwrap();
+        let journey = Journey::from_legs(vec![
+            Leg::walk(Station::SUD, Station::DU, time("05:50"), time("05:55")),
+            train(&[(Station::DU, "06:00"), (Station::MRI, "06:10")]),
+            train(&[(Station::MRI, "06:15"), (Station::DP, "06:40")]),
+        ])
+        .unwrap();
+        assert_eq!(fare_stations(&journey), vec![(Station::DU, Station::DP)]);
+        let fares = HashMap::from([((Station::DU, Station::DP), Some(5000))]);
+        assert_eq!(journey_fare(&journey, &fares), Some(5000));
+    }
+
+    #[test]
+    fn differences_past_midnight_count_forward() {
+        let ranked = |departure: &str, arrival: &str| {
+            let leg = Leg::train(
+                "route".into(),
+                None,
+                run(&[(Station::DP, departure), (Station::MRI, arrival)]),
+            )
+            .unwrap();
+            RankedJourney {
+                journey: Journey::from_legs(vec![leg]).unwrap(),
+                fare: None,
+                tags: vec![],
+                summary: String::new(),
+            }
+        };
+        let best = ranked("23:40", "23:55");
+        let later = ranked("23:50", "00:05");
+        let (tags, summary) = describe_difference(&best, &later);
+        assert_eq!(tags, vec![RouteTag::LaterDeparture]);
+        assert_eq!(summary, "arrives 10 min later, leaves 10 min later");
+    }
+
+    #[test]
+    fn walking_between_trains_starts_a_new_trip() {
+        let train =
+            |stops: &[(Station, &str)]| Leg::train("route".into(), None, run(stops)).unwrap();
+        let journey = Journey::from_legs(vec![
+            train(&[(Station::DU, "06:00"), (Station::SUD, "06:05")]),
+            Leg::walk(Station::SUD, Station::DU, time("06:05"), time("06:10")),
+            train(&[(Station::DU, "06:15"), (Station::DP, "06:40")]),
+        ])
+        .unwrap();
+        assert_eq!(
+            fare_stations(&journey),
+            vec![(Station::DU, Station::SUD), (Station::DU, Station::DP)]
+        );
+        let mut fares = HashMap::from([
+            ((Station::DU, Station::SUD), Some(3000)),
+            ((Station::DU, Station::DP), Some(5000)),
+        ]);
+        assert_eq!(journey_fare(&journey, &fares), Some(8000));
+        fares.insert((Station::DU, Station::SUD), None);
+        assert_eq!(journey_fare(&journey, &fares), None);
+    }
+
+    #[test]
+    fn journeys_past_midnight_have_positive_durations() {
+        let leg = Leg::train(
+            "BOGOR-JAKARTAKOTA".into(),
+            None,
+            run(&[(Station::DP, "23:50"), (Station::MRI, "00:20")]),
+        )
+        .unwrap();
+        let journey = Journey::from_legs(vec![leg]).unwrap();
+        assert_eq!(journey.total_duration, 30);
+        assert_eq!(journey.waiting_time, 0);
+    }
 }
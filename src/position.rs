@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use futures::{stream, StreamExt};
+use serde_derive::Serialize;
+use strum::IntoEnumIterator;
+use utoipa::ToSchema;
+
+use crate::{
+    error::{AppError, AppErrorType},
+    fetch::{fetch_station_schedule, fetch_train_schedule, Upstream},
+    line::TrainLine,
+    model::{service_time, DataWarning, TrainSchedule},
+    station::Station,
+};
+
+/// How far ahead station schedules are scanned to find trains on a line.
+/// Running trains call somewhere on their line well within this window.
+const LINE_SCAN_MINUTES: i64 = 20;
+
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrainStatus {
+    NotYetDeparted,
+    Running,
+    Terminated,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct TrainPosition {
+    pub train_id: String,
+    pub status: TrainStatus,
+    /// Last stop the train has reached. Null before departure.
+    pub last_station: Option<Station>,
+    /// Next stop the train will reach. Null once terminated.
+    pub next_station: Option<Station>,
+    /// Fraction of the way from the last stop to the next one, between 0 and 1.
+    pub progress: Option<f32>,
+    /// Estimated arrival at the next stop.
+    pub eta: Option<NaiveTime>,
+    pub estimated_at: NaiveTime,
+}
+
+/// Trains running on a line, with a warning for every station or train that
+/// could not be fetched and was left out.
+#[derive(Serialize, ToSchema)]
+pub struct LinePositions {
+    pub positions: Vec<TrainPosition>,
+    pub warnings: Vec<DataWarning>,
+}
+
+fn skipped_fetch(what: String, err: &AppError) -> DataWarning {
+    DataWarning {
+        row: None,
+        skipped: true,
+        message: format!("Left out {}: {}", what, err.detail()),
+    }
+}
+
+/// Stop times as offsets from the first stop, so that runs crossing midnight
+/// keep increasing.
+fn unwrap_stop_times(stops: &[TrainSchedule]) -> Vec<Duration> {
+    let mut offsets = Vec::with_capacity(stops.len());
+    let mut day = Duration::zero();
+    let mut previous: Option<NaiveTime> = None;
+    for stop in stops {
+        if let Some(previous) = previous {
+            if stop.time_est < previous {
+                day += Duration::days(1);
+            }
+        }
+        previous = Some(stop.time_est);
+        offsets.push(stop.time_est - stops[0].time_est + day);
+    }
+    offsets
+}
+
+pub fn estimate_position(
+    train_id: &str,
+    stops: &[TrainSchedule],
+    now: NaiveTime,
+) -> Result<TrainPosition, AppError> {
+    let Some(first) = stops.first() else {
+        return Err(AppError {
+            message: Some(format!("No schedule found for train {}", train_id)),
+            cause: None,
+            error_type: AppErrorType::NotFoundError,
+            parameter: None,
+        });
+    };
+    let offsets = unwrap_stop_times(stops);
+    let run_length = *offsets.last().unwrap_or(&Duration::zero());
+    // Measured within the service day, so a run crossing midnight is still
+    // going, or over, early the next morning.
+    let elapsed = service_time(now) - service_time(first.time_est);
+
+    let position = |status, last: Option<usize>, next: Option<usize>, progress| TrainPosition {
+        train_id: train_id.to_string(),
+        status,
+        last_station: last.map(|i| stops[i].station),
+        next_station: next.map(|i| stops[i].station),
+        progress,
+        eta: next.map(|i| stops[i].time_est),
+        estimated_at: now,
+    };
+
+    if elapsed < Duration::zero() {
+        return Ok(position(TrainStatus::NotYetDeparted, None, Some(0), None));
+    }
+    if elapsed >= run_length {
+        return Ok(position(
+            TrainStatus::Terminated,
+            Some(stops.len() - 1),
+            None,
+            None,
+        ));
+    }
+    let next = offsets
+        .iter()
+        .position(|offset| *offset > elapsed)
+        .unwrap_or(stops.len() - 1);
+    let last = next - 1;
+    let segment = (offsets[next] - offsets[last]).num_seconds();
+    let progress = if segment > 0 {
+        (elapsed - offsets[last]).num_seconds() as f32 / segment as f32
+    } else {
+        1.
+    };
+    Ok(position(
+        TrainStatus::Running,
+        Some(last),
+        Some(next),
+        Some(progress),
+    ))
+}
+
+pub async fn fetch_train_position(
+    upstream: &Upstream,
+    train_id: &str,
+    now: NaiveTime,
+) -> Result<TrainPosition, AppError> {
+    let stops = fetch_train_schedule(upstream, train_id).await?;
+    estimate_position(train_id, &stops, now)
+}
+
+/// Every train currently running between two stations of the line. A
+/// station or train that cannot be fetched is left out with a warning, so
+/// this only fails when nothing could be fetched at all.
+pub async fn fetch_line_positions(
+    upstream: &Upstream,
+    line: TrainLine,
+    now: NaiveDateTime,
+    concurrency: usize,
+) -> Result<LinePositions, AppError> {
+    let time_from = now.time();
+    let time_to = (now + Duration::minutes(LINE_SCAN_MINUTES)).time();
+    let time_to = if time_to < time_from {
+        NaiveDateTime::MAX.time()
+    } else {
+        time_to
+    };
+
+    let stations = Station::iter()
+        .filter(|station| station.line().contains(&line))
+        .collect::<Vec<_>>();
+    let station_schedules = stream::iter(stations)
+        .map(|station| async move {
+            let schedule = fetch_station_schedule(upstream, station, time_from, time_to).await;
+            (station, schedule)
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let mut warnings = vec![];
+    let mut first_err = None;
+    let mut fetched = false;
+    let mut train_ids = HashSet::new();
+    for (station, station_schedule) in station_schedules {
+        match station_schedule {
+            Ok(station_schedule) => {
+                fetched = true;
+                train_ids.extend(
+                    station_schedule
+                        .into_iter()
+                        .map(|schedule| schedule.train_id),
+                );
+            }
+            Err(err) => {
+                warnings.push(skipped_fetch(
+                    format!("the schedule of station {}", station.id()),
+                    &err,
+                ));
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    if let (false, Some(err)) = (fetched, first_err) {
+        return Err(err);
+    }
+
+    let positions = stream::iter(train_ids)
+        .map(|train_id| async move {
+            let position = fetch_train_position(upstream, &train_id, now.time()).await;
+            (train_id, position)
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let on_line = |station: Option<Station>| station.is_some_and(|st| st.line().contains(&line));
+    let mut running = vec![];
+    let mut first_err = None;
+    let mut fetched = positions.is_empty();
+    for (train_id, position) in positions {
+        let position = match position {
+            Ok(position) => position,
+            Err(err) => {
+                warnings.push(skipped_fetch(format!("train {}", train_id), &err));
+                first_err.get_or_insert(err);
+                continue;
+            }
+        };
+        fetched = true;
+        if position.status == TrainStatus::Running
+            && on_line(position.last_station)
+            && on_line(position.next_station)
+        {
+            running.push(position);
+        }
+    }
+    if let (false, Some(err)) = (fetched, first_err) {
+        return Err(err);
+    }
+    running.sort_by(|a, b| a.train_id.cmp(&b.train_id));
+    Ok(LinePositions {
+        positions: running,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{run, time};
+
+    fn estimate(stops: &[TrainSchedule], now: &str) -> TrainPosition {
+        estimate_position("1234", stops, time(now)).unwrap()
+    }
+
+    #[test]
+    fn train_waits_before_its_first_stop() {
+        let stops = run(&[(Station::BOO, "05:00"), (Station::DP, "05:30")]);
+        let position = estimate(&stops, "04:50");
+        assert_eq!(position.status, TrainStatus::NotYetDeparted);
+        assert_eq!(position.last_station, None);
+        assert_eq!(position.next_station, Some(Station::BOO));
+        assert_eq!(position.eta, Some(time("05:00")));
+    }
+
+    #[test]
+    fn running_train_is_between_stops() {
+        let stops = run(&[
+            (Station::BOO, "05:00"),
+            (Station::DP, "05:30"),
+            (Station::MRI, "06:00"),
+        ]);
+        let position = estimate(&stops, "05:45");
+        assert_eq!(position.status, TrainStatus::Running);
+        assert_eq!(position.last_station, Some(Station::DP));
+        assert_eq!(position.next_station, Some(Station::MRI));
+        assert_eq!(position.progress, Some(0.5));
+        assert_eq!(position.eta, Some(time("06:00")));
+
+        let position = estimate(&stops, "05:30");
+        assert_eq!(position.last_station, Some(Station::DP));
+        assert_eq!(position.progress, Some(0.));
+    }
+
+    #[test]
+    fn train_terminates_at_its_last_stop() {
+        let stops = run(&[(Station::BOO, "05:00"), (Station::DP, "05:30")]);
+        let position = estimate(&stops, "05:30");
+        assert_eq!(position.status, TrainStatus::Terminated);
+        assert_eq!(position.last_station, Some(Station::DP));
+        assert_eq!(position.next_station, None);
+        assert_eq!(position.eta, None);
+    }
+
+    #[test]
+    fn run_across_midnight_keeps_running() {
+        let stops = run(&[
+            (Station::BOO, "23:40"),
+            (Station::DP, "23:55"),
+            (Station::MRI, "00:15"),
+        ]);
+        let position = estimate(&stops, "00:05");
+        assert_eq!(position.status, TrainStatus::Running);
+        assert_eq!(position.last_station, Some(Station::DP));
+        assert_eq!(position.next_station, Some(Station::MRI));
+        assert_eq!(position.progress, Some(0.5));
+
+        assert_eq!(estimate(&stops, "00:20").status, TrainStatus::Terminated);
+        assert_eq!(
+            estimate(&stops, "23:00").status,
+            TrainStatus::NotYetDeparted
+        );
+    }
+
+    #[test]
+    fn train_without_stops_is_not_found() {
+        let err = estimate_position("1234", &[], time("05:00")).unwrap_err();
+        assert!(matches!(err.error_type, AppErrorType::NotFoundError));
+    }
+}
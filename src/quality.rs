@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::model::DataWarning;
+
+static AFFECTED_RESPONSES: AtomicU64 = AtomicU64::new(0);
+static SKIPPED_ROWS: AtomicU64 = AtomicU64::new(0);
+static PATCHED_ROWS: AtomicU64 = AtomicU64::new(0);
+
+/// Upstream data-quality problems seen since the service started.
+#[derive(Serialize, ToSchema)]
+pub struct DataQualityReport {
+    /// Upstream responses with at least one problem row.
+    pub affected_responses: u64,
+    /// Rows left out because they could not be parsed.
+    pub skipped_rows: u64,
+    /// Rows kept with a placeholder, such as an unknown station.
+    pub patched_rows: u64,
+}
+
+/// Counts the problems found while parsing one upstream response, and logs
+/// them once for the whole response.
+pub fn record(source: &str, warnings: &[DataWarning]) {
+    let Some(first) = warnings.first() else {
+        return;
+    };
+    let skipped = warnings.iter().filter(|warning| warning.skipped).count();
+    let patched = warnings.len() - skipped;
+    AFFECTED_RESPONSES.fetch_add(1, Ordering::Relaxed);
+    SKIPPED_ROWS.fetch_add(skipped as u64, Ordering::Relaxed);
+    PATCHED_ROWS.fetch_add(patched as u64, Ordering::Relaxed);
+    log::warn!(
+        "{}: {} rows skipped, {} patched, first: {}",
+        source,
+        skipped,
+        patched,
+        first.message
+    );
+}
+
+pub fn report() -> DataQualityReport {
+    DataQualityReport {
+        affected_responses: AFFECTED_RESPONSES.load(Ordering::Relaxed),
+        skipped_rows: SKIPPED_ROWS.load(Ordering::Relaxed),
+        patched_rows: PATCHED_ROWS.load(Ordering::Relaxed),
+    }
+}
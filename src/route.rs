@@ -1,33 +1,72 @@
 use std::str::FromStr;
 
-use actix_web::{get, web, HttpResponse, Result};
-use chrono::{Duration, NaiveTime};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Result};
+use chrono::{Duration, NaiveDate, NaiveTime, Weekday};
 use serde_derive::Deserialize;
+use serde_json::Value;
+use strum::ParseError;
+use utoipa::IntoParams;
 
 use crate::{
-    error::AppError,
-    fetch::fetch_distance,
-    fetch::fetch_fare,
-    fetch::fetch_station_schedule,
-    fetch::fetch_train_schedule,
+    analytics::{fetch_headways, HeadwayAnalytics},
+    batch::{run_batch, BatchQuery, BatchResult, MAX_BATCH_SIZE},
+    config::AppConfig,
+    departure::{departure_stream, DepartureHub},
+    error::{parse_param, AppError, AppErrorResponse, AppErrorType, Query, StorageFailureResponse},
+    fetch::fetch_station_schedule_with_warnings,
+    fetch::fetch_train_schedule_with_warnings,
+    fetch::Upstream,
+    format::{tabular_response, NamedId, OutputFormat},
+    ical::journey_calendar,
     line::TrainLine,
-    pathfinder::{choose_fastest_path, generate_all_transit_routes},
+    model::{
+        jakarta_now, Distance, Fare, FastestRoute, FirstLastTrain, RouteAlternatives, RouteProfile,
+        StationSchedule, StationScheduleList, TrainSchedule, TrainScheduleList, TransitRoutes,
+    },
+    pathfinder::{choose_fastest_path, RouteConstraint},
+    position::{fetch_line_positions, fetch_train_position, LinePositions, TrainPosition},
+    quality::{self, DataQualityReport},
     station::Station,
+    store::Store,
+    subscription::{Subscription, SubscriptionParam, SubscriptionStore},
+    timetable::TimetableChange,
+    v1::{
+        self, AllJourneysParam, FirstLastParam, JourneyProfileParam, JourneysParam,
+        StationPairParam,
+    },
 };
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
 #[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
 struct StationScheduleRequestParam {
     station: String,
     time_from: Option<NaiveTime>,
     time_to: Option<NaiveTime>,
+    format: Option<OutputFormat>,
 }
 
+#[utoipa::path(
+    tag = "schedule",
+    params(StationScheduleRequestParam),
+    responses(
+        (status = 200, description = "OK", content(
+            (StationScheduleList = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        )),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
 #[get("/station-schedule")]
 async fn station_schedule(
-    req: web::Query<StationScheduleRequestParam>,
+    req: Query<StationScheduleRequestParam>,
+    upstream: web::Data<Upstream>,
+    http_req: HttpRequest,
 ) -> Result<HttpResponse, AppError> {
-    let station = Station::from_str(&req.station)?;
+    let station = parse_param(&req.station, "station")?;
     let time_from = match req.time_from {
         Some(time) => time,
         None => NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
@@ -36,107 +75,789 @@ async fn station_schedule(
         Some(time) => time,
         None => NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
     };
-    let station_schedule = fetch_station_schedule(station, time_from, time_to).await?;
-    Ok(HttpResponse::Ok().json(station_schedule))
+    let (schedules, warnings) =
+        fetch_station_schedule_with_warnings(&upstream, station, time_from, time_to).await?;
+    let format = OutputFormat::negotiate(req.format, &http_req);
+    let json = StationScheduleList {
+        schedules,
+        warnings,
+    };
+    Ok(tabular_response(format, json, |json| json.schedules))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct DepartureStreamParam {
+    station: String,
+}
+
+#[utoipa::path(
+    tag = "schedule",
+    params(DepartureStreamParam),
+    responses(
+        (status = 200, description = "Server-Sent Events of departure snapshots and diffs", content_type = "text/event-stream", body = String),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/departures/stream")]
+async fn departures_stream(
+    req: Query<DepartureStreamParam>,
+    hub: web::Data<DepartureHub>,
+) -> Result<HttpResponse, AppError> {
+    let station = parse_param(&req.station, "station")?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(departure_stream(hub.into_inner(), station)))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
 #[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
 struct TrainScheduleRequestParam {
     train_id: String,
+    format: Option<OutputFormat>,
 }
 
+#[utoipa::path(
+    tag = "schedule",
+    params(TrainScheduleRequestParam),
+    responses(
+        (status = 200, description = "OK", content(
+            (TrainScheduleList = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        )),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
 #[get("/train-schedule")]
 async fn train_schedule(
-    req: web::Query<TrainScheduleRequestParam>,
+    req: Query<TrainScheduleRequestParam>,
+    upstream: web::Data<Upstream>,
+    http_req: HttpRequest,
 ) -> Result<HttpResponse, AppError> {
-    let train_schedule = fetch_train_schedule(&req.train_id).await?;
-    Ok(HttpResponse::Ok().json(train_schedule))
+    let (schedules, warnings) =
+        fetch_train_schedule_with_warnings(&upstream, &req.train_id).await?;
+    let format = OutputFormat::negotiate(req.format, &http_req);
+    let json = TrainScheduleList {
+        schedules,
+        warnings,
+    };
+    Ok(tabular_response(format, json, |json| json.schedules))
+}
+
+#[utoipa::path(
+    tag = "trains",
+    params(TrainScheduleRequestParam),
+    responses(
+        (status = 200, description = "OK", body = TrainPosition),
+        (status = 404, description = "Unknown train", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/train-position")]
+async fn train_position(
+    req: Query<TrainScheduleRequestParam>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let position = fetch_train_position(&upstream, &req.train_id, jakarta_now().time()).await?;
+    Ok(HttpResponse::Ok().json(position))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
 #[serde(rename_all = "kebab-case")]
-struct RouteInfoParam {
-    station_from: String,
-    station_to: String,
+#[into_params(parameter_in = Query)]
+struct LinePositionParam {
+    line_id: String,
+}
+
+#[utoipa::path(
+    tag = "trains",
+    params(LinePositionParam),
+    responses(
+        (status = 200, description = "Running trains, with a warning for every station or train left out", body = LinePositions),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/train-positions")]
+async fn train_positions(
+    req: Query<LinePositionParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let line = parse_param(&req.line_id, "line-id")?;
+    let positions =
+        fetch_line_positions(&upstream, line, jakarta_now(), config.fetch_concurrency).await?;
+    Ok(HttpResponse::Ok().json(positions))
 }
 
+/// Deprecated alias of `/v1/fares`.
+#[utoipa::path(
+    tag = "fares",
+    params(StationPairParam),
+    responses(
+        (status = 200, description = "OK", body = Fare),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
 #[get("/fare")]
-async fn train_fare(req: web::Query<RouteInfoParam>) -> Result<HttpResponse, AppError> {
-    let station_from = Station::from_str(&req.station_from)?;
-    let station_to = Station::from_str(&req.station_to)?;
-    let fare = fetch_fare(station_from, station_to).await?;
-    Ok(HttpResponse::Ok().json(fare))
+async fn train_fare(
+    req: Query<StationPairParam>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    v1::get_fare(req, upstream).await
 }
 
+/// Deprecated alias of `/v1/distances`.
+#[utoipa::path(
+    tag = "fares",
+    params(StationPairParam),
+    responses(
+        (status = 200, description = "OK", body = Distance),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
 #[get("/distance")]
-async fn distance(req: web::Query<RouteInfoParam>) -> Result<HttpResponse, AppError> {
-    let station_from = Station::from_str(&req.station_from)?;
-    let station_to = Station::from_str(&req.station_to)?;
-    let distance = fetch_distance(station_from, station_to).await?;
-    Ok(HttpResponse::Ok().json(distance))
+async fn distance(
+    req: Query<StationPairParam>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    v1::get_distance(req, upstream).await
+}
+
+fn parse_comma_separated<T: FromStr>(value: &Option<String>) -> Result<Vec<T>, T::Err> {
+    match value {
+        Some(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(T::from_str)
+            .collect(),
+        None => Ok(vec![]),
+    }
+}
+
+/// Parses a comma separated list of ids given in request parameter
+/// `parameter`, reporting an unknown id as an invalid parameter.
+fn parse_id_list<T: FromStr<Err = ParseError>>(
+    value: &Option<String>,
+    parameter: &str,
+) -> Result<Vec<T>, AppError> {
+    let Ok(ids) = parse_comma_separated::<String>(value);
+    ids.iter().map(|id| parse_param(id, parameter)).collect()
 }
 
-#[derive(Deserialize)]
+pub(crate) fn parse_route_constraint(
+    via: &Option<String>,
+    avoid_stations: &Option<String>,
+    avoid_lines: &Option<String>,
+) -> Result<RouteConstraint, AppError> {
+    Ok(RouteConstraint {
+        via: parse_id_list(via, "via")?,
+        avoid_stations: parse_id_list(avoid_stations, "avoid-stations")?,
+        avoid_lines: parse_id_list(avoid_lines, "avoid-lines")?,
+    })
+}
+
+/// Longest transfer time a request may ask for, in minutes.
+pub(crate) const MAX_TRANSIT_MINUTES: i64 = 120;
+
+/// Reads a requested transfer time, none by default and at most
+/// `MAX_TRANSIT_MINUTES`.
+pub(crate) fn parse_transit_duration(minutes: Option<i64>) -> Result<Duration, AppError> {
+    let minutes = minutes.unwrap_or(0);
+    if !(0..=MAX_TRANSIT_MINUTES).contains(&minutes) {
+        return Err(AppError {
+            message: Some(format!(
+                "transit-duration must be between 0 and {} minutes",
+                MAX_TRANSIT_MINUTES
+            )),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("transit-duration".into()),
+        });
+    }
+    Ok(Duration::minutes(minutes))
+}
+
+#[derive(Deserialize, IntoParams)]
 #[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
 struct PathfindFastestParam {
     station_from: String,
     station_to: String,
     time_from: NaiveTime,
     transit_duration: Option<i64>,
+    via: Option<String>,
+    avoid_stations: Option<String>,
+    avoid_lines: Option<String>,
 }
 
+#[utoipa::path(
+    tag = "routing",
+    params(PathfindFastestParam),
+    responses(
+        (status = 200, description = "OK", body = FastestRoute),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
 #[get("/get-fastest-route")]
 async fn get_fastest_route(
-    req: web::Query<PathfindFastestParam>,
+    req: Query<PathfindFastestParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
 ) -> Result<HttpResponse, AppError> {
-    let station_from = Station::from_str(&req.station_from)?;
-    let station_to = Station::from_str(&req.station_to)?;
-    let duration = match req.transit_duration {
-        Some(duration) => Duration::minutes(duration),
-        None => Duration::minutes(0),
-    };
-    let path = choose_fastest_path(station_from, station_to, req.time_from, duration).await?;
-    Ok(HttpResponse::Ok().json(path))
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let duration = parse_transit_duration(req.transit_duration)?;
+    let constraint = parse_route_constraint(&req.via, &req.avoid_stations, &req.avoid_lines)?;
+    let (journey, skipped_paths) = choose_fastest_path(
+        &upstream,
+        station_from,
+        station_to,
+        req.time_from,
+        duration,
+        &constraint,
+        config.fetch_concurrency,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(FastestRoute {
+        journey,
+        skipped_paths,
+    }))
 }
 
-#[derive(Deserialize)]
+const DEFAULT_ALARM_MINUTES: i64 = 15;
+
+#[derive(Deserialize, IntoParams)]
 #[serde(rename_all = "kebab-case")]
-struct PathfindParam {
+#[into_params(parameter_in = Query)]
+struct JourneyCalendarParam {
     station_from: String,
     station_to: String,
+    time_from: NaiveTime,
+    transit_duration: Option<i64>,
+    via: Option<String>,
+    avoid_stations: Option<String>,
+    avoid_lines: Option<String>,
+    date: Option<NaiveDate>,
+    /// Comma separated weekdays, such as `mon,tue,wed,thu,fri`, that turn
+    /// the event into a weekly recurring one, starting on the first of them
+    /// on or after `date`.
+    weekdays: Option<String>,
+    /// Minutes before departure to remind, 0 or more.
+    alarm: Option<i64>,
+}
+
+#[utoipa::path(
+    tag = "routing",
+    params(JourneyCalendarParam),
+    responses(
+        (status = 200, description = "iCalendar with one VEVENT", content_type = "text/calendar", body = String),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/journey.ics")]
+async fn journey_ics(
+    req: Query<JourneyCalendarParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let duration = parse_transit_duration(req.transit_duration)?;
+    let constraint = parse_route_constraint(&req.via, &req.avoid_stations, &req.avoid_lines)?;
+    let weekdays: Vec<Weekday> = parse_comma_separated(&req.weekdays).map_err(|_| AppError {
+        message: Some("weekdays must be a comma separated list of weekday names".into()),
+        cause: None,
+        error_type: AppErrorType::InvalidRequestParameter,
+        parameter: Some("weekdays".into()),
+    })?;
+    let alarm = req.alarm.unwrap_or(DEFAULT_ALARM_MINUTES);
+    if alarm < 0 {
+        return Err(AppError {
+            message: Some("alarm cannot be negative".into()),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("alarm".into()),
+        });
+    }
+    let (journey, _) = choose_fastest_path(
+        &upstream,
+        station_from,
+        station_to,
+        req.time_from,
+        duration,
+        &constraint,
+        config.fetch_concurrency,
+    )
+    .await?;
+    let calendar = journey_calendar(
+        &journey,
+        req.date.unwrap_or_else(|| jakarta_now().date()),
+        &weekdays,
+        alarm,
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .insert_header((
+            "Content-Disposition",
+            "attachment; filename=\"journey.ics\"",
+        ))
+        .body(calendar))
+}
+
+/// Deprecated alias of `/v1/journeys`.
+#[utoipa::path(
+    tag = "routing",
+    params(JourneysParam),
+    responses(
+        (status = 200, description = "OK", body = RouteAlternatives),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/routes")]
+async fn routes(
+    req: Query<JourneysParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    v1::list_journeys(req, config, upstream).await
 }
 
+/// Deprecated alias of `/v1/journeys/profile`.
+#[utoipa::path(
+    tag = "routing",
+    params(JourneyProfileParam),
+    responses(
+        (status = 200, description = "OK", body = RouteProfile),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/get-route-profile")]
+async fn get_route_profile(
+    req: Query<JourneyProfileParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    v1::get_journey_profile(req, config, upstream).await
+}
+
+/// Deprecated alias of `/v1/journeys/first-last`.
+#[utoipa::path(
+    tag = "routing",
+    params(FirstLastParam),
+    responses(
+        (status = 200, description = "OK", body = FirstLastTrain),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/first-last-train")]
+async fn first_last_train(
+    req: Query<FirstLastParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    v1::get_first_last_journeys(req, config, upstream).await
+}
+
+/// Deprecated alias of `/v1/journeys/all`.
+#[utoipa::path(
+    tag = "routing",
+    params(AllJourneysParam),
+    responses(
+        (status = 200, description = "OK", body = TransitRoutes),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
 #[get("/get-all-transit-route")]
-async fn get_transit_route(req: web::Query<PathfindParam>) -> Result<HttpResponse, AppError> {
-    let station_from = Station::from_str(&req.station_from)?;
-    let station_to = Station::from_str(&req.station_to)?;
-    let path = generate_all_transit_routes(station_from, station_to)?;
-    Ok(HttpResponse::Ok().json(path))
+async fn get_transit_route(
+    req: Query<AllJourneysParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    v1::list_all_journeys(req, config, upstream).await
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
 #[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
 struct StationListFilterParam {
     line_id: Option<String>,
     transit_station_only: Option<bool>,
+    format: Option<OutputFormat>,
 }
 
+#[utoipa::path(
+    tag = "stations",
+    params(StationListFilterParam),
+    responses(
+        (status = 200, description = "OK", content(
+            (HashMap<String, String> = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        )),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
 #[get("/station-list")]
-async fn station_list(req: web::Query<StationListFilterParam>) -> Result<HttpResponse, AppError> {
+async fn station_list(
+    req: Query<StationListFilterParam>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
     let line = match &req.line_id {
-        Some(line) => Some(TrainLine::from_str(line)?),
+        Some(line) => Some(parse_param(line, "line-id")?),
         None => None,
     };
 
-    let transit_station_filter = match req.transit_station_only {
-        Some(transit_station) => transit_station,
-        None => false,
-    };
-    Ok(HttpResponse::Ok().json(Station::map_name_to_id(line, transit_station_filter)))
+    let transit_station_filter = req.transit_station_only.unwrap_or_default();
+    let stations = Station::map_name_to_id(line, transit_station_filter);
+    let format = OutputFormat::negotiate(req.format, &http_req);
+    Ok(tabular_response(format, stations, |stations| {
+        NamedId::from_map(&stations)
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct FormatParam {
+    format: Option<OutputFormat>,
 }
 
+#[utoipa::path(
+    tag = "stations",
+    params(FormatParam),
+    responses(
+        (status = 200, description = "OK", content(
+            (HashMap<String, String> = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        ))
+    )
+)]
 #[get("/line-list")]
-async fn line_list() -> Result<HttpResponse, AppError> {
-    Ok(HttpResponse::Ok().json(TrainLine::map_name_to_id()))
+async fn line_list(
+    req: Query<FormatParam>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let lines = TrainLine::map_name_to_id();
+    let format = OutputFormat::negotiate(req.format, &http_req);
+    Ok(tabular_response(format, lines, |lines| {
+        NamedId::from_map(&lines)
+    }))
+}
+
+#[utoipa::path(
+    tag = "subscriptions",
+    request_body = SubscriptionParam,
+    responses(
+        (status = 201, description = "Created", body = Subscription),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        StorageFailureResponse
+    )
+)]
+#[post("/subscriptions")]
+async fn create_subscription(
+    req: web::Json<SubscriptionParam>,
+    store: web::Data<SubscriptionStore>,
+) -> Result<HttpResponse, AppError> {
+    let subscription = store.create(req.into_inner()).await?;
+    Ok(HttpResponse::Created().json(subscription))
+}
+
+#[utoipa::path(
+    tag = "subscriptions",
+    responses(
+        (status = 200, description = "OK", body = Vec<Subscription>),
+        StorageFailureResponse
+    )
+)]
+#[get("/subscriptions")]
+async fn subscription_list(store: web::Data<SubscriptionStore>) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(store.list().await?))
+}
+
+#[utoipa::path(
+    tag = "subscriptions",
+    params(("id" = u64, Path, description = "Subscription id")),
+    responses(
+        (status = 200, description = "OK", body = Subscription),
+        (status = 404, description = "Unknown subscription", body = AppErrorResponse, content_type = "application/problem+json"),
+        StorageFailureResponse
+    )
+)]
+#[get("/subscriptions/{id}")]
+async fn subscription_detail(
+    id: web::Path<u64>,
+    store: web::Data<SubscriptionStore>,
+) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(store.get(*id).await?))
+}
+
+#[utoipa::path(
+    tag = "subscriptions",
+    params(("id" = u64, Path, description = "Subscription id")),
+    request_body = SubscriptionParam,
+    responses(
+        (status = 200, description = "OK", body = Subscription),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown subscription", body = AppErrorResponse, content_type = "application/problem+json"),
+        StorageFailureResponse
+    )
+)]
+#[put("/subscriptions/{id}")]
+async fn update_subscription(
+    id: web::Path<u64>,
+    req: web::Json<SubscriptionParam>,
+    store: web::Data<SubscriptionStore>,
+) -> Result<HttpResponse, AppError> {
+    let subscription = store.update(*id, req.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(subscription))
+}
+
+#[utoipa::path(
+    tag = "subscriptions",
+    params(("id" = u64, Path, description = "Subscription id")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "Unknown subscription", body = AppErrorResponse, content_type = "application/problem+json"),
+        StorageFailureResponse
+    )
+)]
+#[delete("/subscriptions/{id}")]
+async fn delete_subscription(
+    id: web::Path<u64>,
+    store: web::Data<SubscriptionStore>,
+) -> Result<HttpResponse, AppError> {
+    store.delete(*id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    tag = "history",
+    responses(
+        (status = 200, description = "Dates with stored schedules, newest first", body = Vec<NaiveDate>),
+        StorageFailureResponse
+    )
+)]
+#[get("/history/dates")]
+async fn history_dates(store: web::Data<Store>) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(store.run(|store| store.dates()).await?))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct HistoryStationScheduleParam {
+    station: String,
+    date: NaiveDate,
+    time_from: Option<NaiveTime>,
+    time_to: Option<NaiveTime>,
+}
+
+#[utoipa::path(
+    tag = "history",
+    params(HistoryStationScheduleParam),
+    responses(
+        (status = 200, description = "OK", body = Vec<StationSchedule>),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        StorageFailureResponse
+    )
+)]
+#[get("/history/station-schedule")]
+async fn history_station_schedule(
+    req: Query<HistoryStationScheduleParam>,
+    store: web::Data<Store>,
+) -> Result<HttpResponse, AppError> {
+    let station = parse_param(&req.station, "station")?;
+    let time_from = match req.time_from {
+        Some(time) => time,
+        None => NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+    };
+    let time_to = match req.time_to {
+        Some(time) => time,
+        None => NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+    };
+    let date = req.date;
+    let schedules = store
+        .run(move |store| store.station_schedule(station, date, time_from, time_to))
+        .await?;
+    Ok(HttpResponse::Ok().json(schedules))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct HistoryTrainScheduleParam {
+    train_id: String,
+    date: NaiveDate,
+}
+
+#[utoipa::path(
+    tag = "history",
+    params(HistoryTrainScheduleParam),
+    responses(
+        (status = 200, description = "OK", body = Vec<TrainSchedule>),
+        StorageFailureResponse
+    )
+)]
+#[get("/history/train-schedule")]
+async fn history_train_schedule(
+    req: Query<HistoryTrainScheduleParam>,
+    store: web::Data<Store>,
+) -> Result<HttpResponse, AppError> {
+    let HistoryTrainScheduleParam { train_id, date } = req.into_inner();
+    let schedules = store
+        .run(move |store| store.train_schedule(&train_id, date))
+        .await?;
+    Ok(HttpResponse::Ok().json(schedules))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct TimetableChangesParam {
+    since: NaiveDate,
+}
+
+#[utoipa::path(
+    tag = "history",
+    params(TimetableChangesParam),
+    responses(
+        (status = 200, description = "OK", body = Vec<TimetableChange>),
+        StorageFailureResponse
+    )
+)]
+#[get("/timetable/changes")]
+async fn timetable_changes(
+    req: Query<TimetableChangesParam>,
+    store: web::Data<Store>,
+) -> Result<HttpResponse, AppError> {
+    let since = req.since;
+    let changes = store
+        .run(move |store| store.timetable_changes(since))
+        .await?;
+    Ok(HttpResponse::Ok().json(changes))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct HeadwayParam {
+    station: String,
+    line: Option<String>,
+    direction: Option<String>,
+    from: Option<NaiveTime>,
+    to: Option<NaiveTime>,
+}
+
+#[utoipa::path(
+    tag = "analytics",
+    params(HeadwayParam),
+    responses(
+        (status = 200, description = "OK", body = HeadwayAnalytics),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/analytics/headways")]
+async fn headways(
+    req: Query<HeadwayParam>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station = parse_param(&req.station, "station")?;
+    let line = match &req.line {
+        Some(line) => Some(parse_param(line, "line")?),
+        None => None,
+    };
+    let direction = match &req.direction {
+        Some(direction) => Some(parse_param(direction, "direction")?),
+        None => None,
+    };
+    let time_from = match req.from {
+        Some(time) => time,
+        None => NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+    };
+    let time_to = match req.to {
+        Some(time) => time,
+        None => NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+    };
+    if time_from > time_to {
+        return Err(AppError {
+            message: Some("from cannot be later than to".into()),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("from".into()),
+        });
+    }
+    let analytics = fetch_headways(&upstream, station, line, direction, time_from, time_to).await?;
+    Ok(HttpResponse::Ok().json(analytics))
+}
+
+#[utoipa::path(
+    tag = "schedule",
+    request_body = Vec<BatchQuery>,
+    responses(
+        (status = 200, description = "One result per query, in request order. A malformed query fails in its own slot", body = Vec<BatchResult>),
+        (status = 400, description = "Body is not an array, or holds too many queries", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[post("/batch")]
+async fn batch_queries(
+    req: web::Json<Vec<Value>>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let queries = req.into_inner();
+    if queries.len() > MAX_BATCH_SIZE {
+        return Err(AppError {
+            message: Some(format!(
+                "A batch cannot have more than {} queries",
+                MAX_BATCH_SIZE
+            )),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("body".into()),
+        });
+    }
+    Ok(HttpResponse::Ok().json(run_batch(&upstream, queries, config.fetch_concurrency).await))
+}
+
+#[utoipa::path(
+    tag = "analytics",
+    responses((status = 200, description = "OK", body = DataQualityReport))
+)]
+#[get("/data-quality")]
+async fn data_quality() -> HttpResponse {
+    HttpResponse::Ok().json(quality::report())
 }
@@ -1,11 +1,44 @@
+use chrono::Duration;
 use serde::{ser::SerializeMap, Serialize};
 use serde_json::{Map, Value};
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString};
+use utoipa::{
+    openapi::{ObjectBuilder, RefOr, Schema, Type},
+    PartialSchema, ToSchema,
+};
 
 use crate::line::TrainLine;
 
-#[derive(EnumIter, EnumString, Debug, Clone, Copy, Eq, PartialEq)]
+/// Minutes needed to change trains, keyed on the station and on the lines of
+/// the train arrived on and the train departed on. Interchanges where the
+/// lines use distant platforms take longer than a same-line change.
+const TRANSFER_MINUTES: &[(Station, TrainLine, TrainLine, i64)] = &[
+    (Station::MRI, TrainLine::B, TrainLine::C, 12),
+    (Station::MRI, TrainLine::C, TrainLine::B, 12),
+    (Station::THB, TrainLine::R, TrainLine::C, 7),
+    (Station::THB, TrainLine::C, TrainLine::R, 7),
+    (Station::JAKK, TrainLine::B, TrainLine::TP, 4),
+    (Station::JAKK, TrainLine::TP, TrainLine::B, 4),
+    (Station::KPB, TrainLine::C, TrainLine::TP, 4),
+    (Station::KPB, TrainLine::TP, TrainLine::C, 4),
+    (Station::DU, TrainLine::C, TrainLine::T, 3),
+    (Station::DU, TrainLine::T, TrainLine::C, 3),
+];
+
+/// Minutes needed for any other change of trains at these stations.
+const STATION_TRANSFER_MINUTES: &[(Station, i64)] = &[
+    (Station::MRI, 8),
+    (Station::THB, 5),
+    (Station::JAKK, 4),
+    (Station::KPB, 4),
+    (Station::DU, 3),
+];
+
+const DEFAULT_TRANSFER_MINUTES: i64 = 2;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(EnumIter, EnumString, Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Station {
     TNG,
     TTI,
@@ -89,6 +122,10 @@ pub enum Station {
     PLM,
     AC,
     TPK,
+    /// Placeholder for station ids upstream reports but this service does
+    /// not know yet. Never parsed nor listed.
+    #[strum(disabled)]
+    Unknown,
 }
 
 impl Station {
@@ -176,9 +213,16 @@ impl Station {
             Station::PLM => "PALMERAH",
             Station::AC => "ANCOL",
             Station::TPK => "TANJUNGPRIUK",
+            Station::Unknown => "UNKNOWN",
         }
     }
 
+    /// Looks a station up by the upstream name used in route names, such as
+    /// `JAKARTAKOTA`.
+    pub fn from_name(name: &str) -> Option<Station> {
+        Station::iter().find(|station| station.name() == name)
+    }
+
     pub fn id(&self) -> &str {
         match *self {
             Station::TNG => "TNG",
@@ -263,6 +307,7 @@ impl Station {
             Station::PLM => "PLM",
             Station::AC => "AC",
             Station::TPK => "TPK",
+            Station::Unknown => "UNKNOWN",
         }
     }
 
@@ -350,18 +395,67 @@ impl Station {
             Station::PLM => vec![TrainLine::R],
             Station::AC => vec![TrainLine::TP],
             Station::TPK => vec![TrainLine::TP],
+            Station::Unknown => vec![],
         }
     }
     pub fn is_transit_station(&self) -> bool {
+        matches!(
+            *self,
+            Station::DU | Station::MRI | Station::JAKK | Station::KPB | Station::THB
+        )
+    }
+
+    /// Minimum time needed to change trains at this station, from a train of
+    /// `arriving` to one of `departing`. A line that is not known falls back
+    /// to the station's own minimum.
+    pub fn min_transfer_time(
+        &self,
+        arriving: Option<TrainLine>,
+        departing: Option<TrainLine>,
+    ) -> Duration {
+        let by_lines = TRANSFER_MINUTES.iter().find(|(station, from, to, _)| {
+            station == self && Some(*from) == arriving && Some(*to) == departing
+        });
+        let by_station = STATION_TRANSFER_MINUTES
+            .iter()
+            .find(|(station, _)| station == self);
+        let minutes = match (by_lines, by_station) {
+            (Some((_, _, _, minutes)), _) | (None, Some((_, minutes))) => *minutes,
+            (None, None) => DEFAULT_TRANSFER_MINUTES,
+        };
+        Duration::minutes(minutes)
+    }
+
+    /// Stations that are a short walk away from this one, outside the station
+    /// building itself.
+    pub fn footpath(&self) -> Vec<Footpath> {
         match *self {
-            Station::DU => true,
-            Station::MRI => true,
-            Station::JAKK => true,
-            Station::KPB => true,
-            Station::THB => true,
-            _ => false,
+            Station::SUD => vec![Footpath {
+                station: Station::SUDB,
+                duration: Duration::minutes(6),
+            }],
+            Station::SUDB => vec![Footpath {
+                station: Station::SUD,
+                duration: Duration::minutes(6),
+            }],
+            Station::THB => vec![Footpath {
+                station: Station::KAT,
+                duration: Duration::minutes(15),
+            }],
+            Station::KAT => vec![Footpath {
+                station: Station::THB,
+                duration: Duration::minutes(15),
+            }],
+            _ => vec![],
         }
     }
+
+    pub fn footpath_to(&self, other: &Station) -> Option<Footpath> {
+        self.footpath()
+            .into_iter()
+            .find(|footpath| footpath.station == *other)
+    }
+
     pub fn map_name_to_id(
         line_opt: Option<TrainLine>,
         transit_station_only: bool,
@@ -399,3 +493,22 @@ impl Serialize for Station {
         map.end()
     }
 }
+
+impl PartialSchema for Station {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .property("name", ObjectBuilder::new().schema_type(Type::String))
+            .property("id", ObjectBuilder::new().schema_type(Type::String))
+            .required("name")
+            .required("id")
+            .into()
+    }
+}
+
+impl ToSchema for Station {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Footpath {
+    pub station: Station,
+    pub duration: Duration,
+}
@@ -0,0 +1,634 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::web;
+use chrono::{NaiveDate, NaiveTime};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    error::{AppError, AppErrorType},
+    model::{jakarta_now, RouteInfoDTO, StationSchedule, TrainSchedule},
+    station::Station,
+    subscription::{AlertRecord, Subscription},
+    timetable::TimetableChange,
+};
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const TIME_FORMAT: &str = "%H:%M:%S";
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many
+/// of them a database has already seen, so entries must never be edited or
+/// reordered once released; add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE station_schedule (
+        date TEXT NOT NULL,
+        station_id TEXT NOT NULL,
+        train_id TEXT NOT NULL,
+        route_name TEXT NOT NULL,
+        time_est TEXT NOT NULL,
+        fetched_at TEXT NOT NULL,
+        PRIMARY KEY (date, station_id, train_id, time_est)
+    );
+    CREATE TABLE train_schedule (
+        date TEXT NOT NULL,
+        train_id TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        station_id TEXT NOT NULL,
+        time_est TEXT NOT NULL,
+        fetched_at TEXT NOT NULL,
+        PRIMARY KEY (date, train_id, seq)
+    );
+    CREATE TABLE route_info (
+        date TEXT NOT NULL,
+        station_from TEXT NOT NULL,
+        station_to TEXT NOT NULL,
+        fare INTEGER NOT NULL,
+        distance TEXT NOT NULL,
+        fetched_at TEXT NOT NULL,
+        PRIMARY KEY (date, station_from, station_to)
+    );",
+    "CREATE TABLE timetable_snapshot (
+        date TEXT PRIMARY KEY,
+        completed_at TEXT NOT NULL,
+        station_count INTEGER NOT NULL,
+        train_count INTEGER NOT NULL
+    );
+    CREATE TABLE timetable_change (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        from_date TEXT NOT NULL,
+        to_date TEXT NOT NULL,
+        diff TEXT NOT NULL
+    );",
+    "CREATE TABLE subscription (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        body TEXT NOT NULL
+    );",
+];
+
+/// Harvested upstream data, keyed by the Jakarta date it was fetched on.
+/// Clones share the same connection.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        AppError {
+            cause: Some(err.to_string()),
+            message: Some("Local storage failed".into()),
+            error_type: AppErrorType::StorageError,
+            parameter: None,
+        }
+    }
+}
+
+fn stored_data_error(cause: String) -> AppError {
+    AppError {
+        message: Some("Stored data is corrupt".into()),
+        cause: Some(cause),
+        error_type: AppErrorType::StorageError,
+        parameter: None,
+    }
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, AppError> {
+    NaiveDate::parse_from_str(date, DATE_FORMAT).map_err(|err| stored_data_error(err.to_string()))
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, AppError> {
+    NaiveTime::parse_from_str(time, TIME_FORMAT).map_err(|err| stored_data_error(err.to_string()))
+}
+
+/// Stored station ids are kept as upstream sent them, so one this service
+/// does not know becomes the placeholder again, with the id preserved.
+fn parse_station(station_id: String) -> (Station, Option<String>) {
+    match Station::from_str(&station_id) {
+        Ok(station) => (station, None),
+        Err(_) => (Station::Unknown, Some(station_id)),
+    }
+}
+
+impl Store {
+    pub fn open(path: &Path) -> Result<Self, AppError> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `query` on the blocking thread pool, so SQLite never stalls the
+    /// async workers. Every access from async code goes through here.
+    pub async fn run<T, F>(&self, query: F) -> Result<T, AppError>
+    where
+        F: FnOnce(&Store) -> Result<T, AppError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.clone();
+        web::block(move || query(&store))
+            .await
+            .map_err(|err| AppError {
+                message: Some("Local storage failed".into()),
+                cause: Some(err.to_string()),
+                error_type: AppErrorType::StorageError,
+                parameter: None,
+            })?
+    }
+
+    pub fn save_station_schedule(
+        &self,
+        date: NaiveDate,
+        station: Station,
+        schedules: &[StationSchedule],
+    ) -> Result<(), AppError> {
+        let fetched_at = fetched_at();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for schedule in schedules {
+            tx.execute(
+                "INSERT OR REPLACE INTO station_schedule
+                    (date, station_id, train_id, route_name, time_est, fetched_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    date.format(DATE_FORMAT).to_string(),
+                    station.id(),
+                    schedule.train_id,
+                    schedule.route_name,
+                    schedule.time_est.format(TIME_FORMAT).to_string(),
+                    fetched_at,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn save_train_schedule(
+        &self,
+        date: NaiveDate,
+        train_id: &str,
+        schedules: &[TrainSchedule],
+    ) -> Result<(), AppError> {
+        let date = date.format(DATE_FORMAT).to_string();
+        let fetched_at = fetched_at();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM train_schedule WHERE date = ?1 AND train_id = ?2",
+            params![date, train_id],
+        )?;
+        for (seq, schedule) in schedules.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO train_schedule (date, train_id, seq, station_id, time_est, fetched_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    date,
+                    train_id,
+                    seq as i64,
+                    schedule.station_id(),
+                    schedule.time_est.format(TIME_FORMAT).to_string(),
+                    fetched_at,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn save_route_info(
+        &self,
+        date: NaiveDate,
+        station_from: Station,
+        station_to: Station,
+        route_info: &RouteInfoDTO,
+    ) -> Result<(), AppError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO route_info
+                (date, station_from, station_to, fare, distance, fetched_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                date.format(DATE_FORMAT).to_string(),
+                station_from.id(),
+                station_to.id(),
+                route_info.fare,
+                route_info.distance,
+                fetched_at(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Dates that have any harvested schedule, newest first.
+    pub fn dates(&self) -> Result<Vec<NaiveDate>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT date FROM station_schedule
+             UNION SELECT date FROM train_schedule
+             ORDER BY date DESC",
+        )?;
+        let dates = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        dates.iter().map(|date| parse_date(date)).collect()
+    }
+
+    /// Latest date on or before `date` with a harvested schedule for the station.
+    fn latest_station_date(
+        &self,
+        station: Station,
+        date: NaiveDate,
+    ) -> Result<Option<String>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let latest = conn
+            .query_row(
+                "SELECT MAX(date) FROM station_schedule WHERE station_id = ?1 AND date <= ?2",
+                params![station.id(), date.format(DATE_FORMAT).to_string()],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(latest)
+    }
+
+    pub fn station_schedule(
+        &self,
+        station: Station,
+        date: NaiveDate,
+        time_from: NaiveTime,
+        time_to: NaiveTime,
+    ) -> Result<Vec<StationSchedule>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT train_id, route_name, time_est FROM station_schedule
+             WHERE date = ?1 AND station_id = ?2 AND time_est >= ?3 AND time_est <= ?4
+             ORDER BY time_est",
+        )?;
+        let rows = stmt
+            .query_map(
+                params![
+                    date.format(DATE_FORMAT).to_string(),
+                    station.id(),
+                    time_from.format(TIME_FORMAT).to_string(),
+                    time_to.format(TIME_FORMAT).to_string(),
+                ],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(train_id, route_name, time_est)| {
+                Ok(StationSchedule {
+                    train_id,
+                    route_name,
+                    time_est: parse_time(&time_est)?,
+                })
+            })
+            .collect()
+    }
+
+    pub fn train_schedule(
+        &self,
+        train_id: &str,
+        date: NaiveDate,
+    ) -> Result<Vec<TrainSchedule>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT station_id, time_est FROM train_schedule
+             WHERE date = ?1 AND train_id = ?2
+             ORDER BY seq",
+        )?;
+        let rows = stmt
+            .query_map(
+                params![date.format(DATE_FORMAT).to_string(), train_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(station_id, time_est)| {
+                let (station, unknown_station_id) = parse_station(station_id);
+                Ok(TrainSchedule {
+                    train_id: train_id.to_string(),
+                    station,
+                    unknown_station_id,
+                    time_est: parse_time(&time_est)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Most recently stored route info on or before `date`, with the date it
+    /// was stored on.
+    pub fn route_info(
+        &self,
+        station_from: Station,
+        station_to: Station,
+        date: NaiveDate,
+    ) -> Result<Option<(NaiveDate, RouteInfoDTO)>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let route_info = conn
+            .query_row(
+                "SELECT fare, distance, date FROM route_info
+                 WHERE station_from = ?1 AND station_to = ?2 AND date <= ?3
+                 ORDER BY date DESC LIMIT 1",
+                params![
+                    station_from.id(),
+                    station_to.id(),
+                    date.format(DATE_FORMAT).to_string()
+                ],
+                |row| {
+                    Ok((
+                        RouteInfoDTO {
+                            fare: row.get(0)?,
+                            distance: row.get(1)?,
+                        },
+                        row.get::<_, String>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        route_info
+            .map(|(route_info, date)| Ok((parse_date(&date)?, route_info)))
+            .transpose()
+    }
+
+    /// Station schedule from the most recent harvest on or before `date`,
+    /// used when upstream is unavailable. KRL runs the same timetable daily
+    /// until a new one is published, so older days are a usable stand-in.
+    /// Returns the date of the harvest used along with the schedule.
+    pub fn latest_station_schedule(
+        &self,
+        station: Station,
+        date: NaiveDate,
+        time_from: NaiveTime,
+        time_to: NaiveTime,
+    ) -> Result<Option<(NaiveDate, Vec<StationSchedule>)>, AppError> {
+        let Some(latest) = self.latest_station_date(station, date)? else {
+            return Ok(None);
+        };
+        let latest = parse_date(&latest)?;
+        Ok(Some((
+            latest,
+            self.station_schedule(station, latest, time_from, time_to)?,
+        )))
+    }
+
+    /// Train schedule from the most recent harvest on or before `date`,
+    /// with the date of that harvest.
+    pub fn latest_train_schedule(
+        &self,
+        train_id: &str,
+        date: NaiveDate,
+    ) -> Result<Option<(NaiveDate, Vec<TrainSchedule>)>, AppError> {
+        let latest = {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row(
+                "SELECT MAX(date) FROM train_schedule WHERE train_id = ?1 AND date <= ?2",
+                params![train_id, date.format(DATE_FORMAT).to_string()],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten()
+        };
+        let Some(latest) = latest else {
+            return Ok(None);
+        };
+        let latest = parse_date(&latest)?;
+        Ok(Some((latest, self.train_schedule(train_id, latest)?)))
+    }
+
+    pub fn record_snapshot(
+        &self,
+        date: NaiveDate,
+        station_count: usize,
+        train_count: usize,
+    ) -> Result<(), AppError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO timetable_snapshot (date, completed_at, station_count, train_count)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                date.format(DATE_FORMAT).to_string(),
+                fetched_at(),
+                station_count as i64,
+                train_count as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn has_snapshot(&self, date: NaiveDate) -> Result<bool, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM timetable_snapshot WHERE date = ?1",
+                params![date.format(DATE_FORMAT).to_string()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// The latest complete snapshot taken before `date`.
+    pub fn previous_snapshot(&self, date: NaiveDate) -> Result<Option<NaiveDate>, AppError> {
+        let previous = {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row(
+                "SELECT MAX(date) FROM timetable_snapshot WHERE date < ?1",
+                params![date.format(DATE_FORMAT).to_string()],
+                |row| row.get::<_, Option<String>>(0),
+            )?
+        };
+        previous.as_deref().map(parse_date).transpose()
+    }
+
+    /// Route name of every train seen at any station on `date`.
+    pub fn train_routes(&self, date: NaiveDate) -> Result<HashMap<String, String>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT train_id, route_name FROM station_schedule WHERE date = ?1 GROUP BY train_id",
+        )?;
+        let routes = stmt
+            .query_map(params![date.format(DATE_FORMAT).to_string()], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(routes)
+    }
+
+    /// Every stored train schedule of `date`, stops in running order.
+    pub fn train_stops(
+        &self,
+        date: NaiveDate,
+    ) -> Result<HashMap<String, Vec<TrainSchedule>>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT train_id, station_id, time_est FROM train_schedule
+             WHERE date = ?1
+             ORDER BY train_id, seq",
+        )?;
+        let rows = stmt
+            .query_map(params![date.format(DATE_FORMAT).to_string()], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut trains: HashMap<String, Vec<TrainSchedule>> = HashMap::new();
+        for (train_id, station_id, time_est) in rows {
+            let (station, unknown_station_id) = parse_station(station_id);
+            trains
+                .entry(train_id.clone())
+                .or_default()
+                .push(TrainSchedule {
+                    train_id,
+                    station,
+                    unknown_station_id,
+                    time_est: parse_time(&time_est)?,
+                });
+        }
+        Ok(trains)
+    }
+
+    pub fn save_timetable_change(&self, change: &TimetableChange) -> Result<(), AppError> {
+        let diff =
+            serde_json::to_string(change).map_err(|err| stored_data_error(err.to_string()))?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO timetable_change (from_date, to_date, diff) VALUES (?1, ?2, ?3)",
+            params![
+                change.from_date.format(DATE_FORMAT).to_string(),
+                change.to_date.format(DATE_FORMAT).to_string(),
+                diff,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Changes detected in snapshots taken on or after `since`, oldest first.
+    pub fn timetable_changes(&self, since: NaiveDate) -> Result<Vec<TimetableChange>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT diff FROM timetable_change WHERE to_date >= ?1 ORDER BY to_date, id",
+        )?;
+        let diffs = stmt
+            .query_map(params![since.format(DATE_FORMAT).to_string()], |row| {
+                row.get::<_, String>(0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        diffs
+            .iter()
+            .map(|diff| {
+                serde_json::from_str(diff).map_err(|err| stored_data_error(err.to_string()))
+            })
+            .collect()
+    }
+
+    pub fn subscriptions(&self) -> Result<Vec<Subscription>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id, body FROM subscription ORDER BY id")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.iter()
+            .map(|(id, body)| parse_subscription(*id, body))
+            .collect()
+    }
+
+    pub fn subscription(&self, id: u64) -> Result<Option<Subscription>, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let body = conn
+            .query_row(
+                "SELECT body FROM subscription WHERE id = ?1",
+                params![id as i64],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        body.map(|body| parse_subscription(id as i64, &body))
+            .transpose()
+    }
+
+    /// Stores a new subscription, returning the id it was given.
+    pub fn insert_subscription(&self, subscription: &Subscription) -> Result<u64, AppError> {
+        let body = subscription_body(subscription)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute("INSERT INTO subscription (body) VALUES (?1)", params![body])?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    /// Replaces a subscription, returning false when it does not exist.
+    pub fn replace_subscription(&self, subscription: &Subscription) -> Result<bool, AppError> {
+        let body = subscription_body(subscription)?;
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE subscription SET body = ?2 WHERE id = ?1",
+            params![subscription.id as i64, body],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Deletes a subscription, returning false when it does not exist.
+    pub fn delete_subscription(&self, id: u64) -> Result<bool, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn.execute("DELETE FROM subscription WHERE id = ?1", params![id as i64])?;
+        Ok(deleted > 0)
+    }
+
+    /// Sets the last alert of a subscription alone, so an edit made while the
+    /// alert was being sent is kept.
+    pub fn record_subscription_alert(&self, id: u64, alert: &AlertRecord) -> Result<(), AppError> {
+        let alert =
+            serde_json::to_string(alert).map_err(|err| stored_data_error(err.to_string()))?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE subscription SET body = json_set(body, '$.last_alert', json(?2)) WHERE id = ?1",
+            params![id as i64, alert],
+        )?;
+        Ok(())
+    }
+}
+
+/// Subscriptions are kept as JSON in `body`, keyed by the `id` column. The
+/// id inside `body` is ignored.
+fn parse_subscription(id: i64, body: &str) -> Result<Subscription, AppError> {
+    let subscription: Subscription =
+        serde_json::from_str(body).map_err(|err| stored_data_error(err.to_string()))?;
+    Ok(Subscription {
+        id: id as u64,
+        ..subscription
+    })
+}
+
+fn subscription_body(subscription: &Subscription) -> Result<String, AppError> {
+    serde_json::to_string(subscription).map_err(|err| stored_data_error(err.to_string()))
+}
+
+fn fetched_at() -> String {
+    jakarta_now().format(DATETIME_FORMAT).to_string()
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), AppError> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let tx = conn.transaction()?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
@@ -0,0 +1,417 @@
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    sync::Arc,
+    time::Duration as StdDuration,
+};
+
+use actix_web::{rt, web};
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime, Weekday};
+use futures::{stream, StreamExt};
+use reqwest::{redirect, Url};
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    error::{parse_param, AppError, AppErrorType},
+    fetch::Upstream,
+    model::{jakarta_now, Journey},
+    pathfinder::{choose_fastest_path, RouteConstraint},
+    route::MAX_TRANSIT_MINUTES,
+    station::Station,
+    store::Store,
+};
+
+const SCHEDULER_TICK_SECONDS: u64 = 60;
+/// How long a webhook gets to accept an alert.
+const WEBHOOK_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+#[derive(Deserialize, ToSchema, Debug, Clone)]
+pub struct SubscriptionParam {
+    pub station_from: String,
+    pub station_to: String,
+    pub departure: NaiveTime,
+    #[schema(value_type = Vec<String>, example = json!(["Mon", "Tue", "Wed", "Thu", "Fri"]))]
+    pub weekdays: Vec<Weekday>,
+    /// Public http or https url alerts are POSTed to.
+    pub webhook_url: String,
+    /// Minutes needed to change trains, from 0 to 120.
+    pub transit_duration: Option<i64>,
+}
+
+impl SubscriptionParam {
+    async fn validate(&self) -> Result<(Station, Station), AppError> {
+        let invalid = |message: &str, parameter: &str| AppError {
+            message: Some(message.into()),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some(parameter.into()),
+        };
+
+        let station_from = parse_param(&self.station_from, "station_from")?;
+        let station_to = parse_param(&self.station_to, "station_to")?;
+        if station_from == station_to {
+            return Err(invalid(
+                "Station from and station cannot be the same",
+                "station_to",
+            ));
+        }
+        if self.weekdays.is_empty() {
+            return Err(invalid("At least one weekday is required", "weekdays"));
+        }
+        if self
+            .transit_duration
+            .is_some_and(|minutes| !(0..=MAX_TRANSIT_MINUTES).contains(&minutes))
+        {
+            return Err(invalid(
+                &format!(
+                    "Transit duration must be between 0 and {} minutes",
+                    MAX_TRANSIT_MINUTES
+                ),
+                "transit_duration",
+            ));
+        }
+        webhook_addrs(&webhook_url(&self.webhook_url)?).await?;
+        Ok((station_from, station_to))
+    }
+}
+
+fn invalid_webhook(cause: impl ToString) -> AppError {
+    AppError {
+        message: Some("Webhook url must be a public http or https url".into()),
+        cause: Some(cause.to_string()),
+        error_type: AppErrorType::InvalidRequestParameter,
+        parameter: Some("webhook_url".into()),
+    }
+}
+
+fn webhook_url(webhook_url: &str) -> Result<Url, AppError> {
+    let url = Url::parse(webhook_url).map_err(invalid_webhook)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid_webhook(format!(
+            "Unsupported scheme {}",
+            url.scheme()
+        )));
+    }
+    if url.host_str().is_none() {
+        return Err(invalid_webhook("The url has no host"));
+    }
+    Ok(url)
+}
+
+/// Whether an address belongs to the public internet. Webhooks are given by
+/// users, so posting anywhere else would let them reach this host or the
+/// network behind it.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Carrier grade NAT, 100.64.0.0/10.
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7, and link local, fe80::/10.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Addresses the webhook host resolves to, failing unless all of them are
+/// public.
+async fn webhook_addrs(url: &Url) -> Result<Vec<SocketAddr>, AppError> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => {
+            let lookup = (host.to_string(), port);
+            web::block(move || lookup.to_socket_addrs())
+                .await
+                .map_err(invalid_webhook)?
+                .map_err(invalid_webhook)?
+                .collect()
+        }
+    };
+    if addrs.is_empty() {
+        return Err(invalid_webhook(format!("{} does not resolve", host)));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(invalid_webhook(format!(
+            "{} resolves to {}, which is not public",
+            host,
+            addr.ip()
+        )));
+    }
+    Ok(addrs)
+}
+
+/// A client that only reaches the checked addresses of the webhook, so the
+/// host cannot resolve to another address by the time the alert is sent, and
+/// that never follows redirects elsewhere.
+pub(crate) async fn webhook_client(webhook_url: &str) -> Result<(reqwest::Client, Url), AppError> {
+    let url = self::webhook_url(webhook_url)?;
+    let addrs = webhook_addrs(&url).await?;
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .resolve_to_addrs(url.host_str().unwrap_or_default(), &addrs)
+        .build()?;
+    Ok((client, url))
+}
+
+/// The last alert sent for a subscription, kept to detect schedule changes.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct AlertRecord {
+    pub date: NaiveDate,
+    /// Train id and boarding time of every train leg that was recommended.
+    #[schema(value_type = Vec<Vec<String>>)]
+    pub trains: Vec<(String, NaiveTime)>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Subscription {
+    pub id: u64,
+    pub station_from: String,
+    pub station_to: String,
+    pub departure: NaiveTime,
+    #[schema(value_type = Vec<String>)]
+    pub weekdays: Vec<Weekday>,
+    pub webhook_url: String,
+    pub transit_duration: Option<i64>,
+    pub last_alert: Option<AlertRecord>,
+}
+
+impl Subscription {
+    fn new(id: u64, param: SubscriptionParam) -> Self {
+        Subscription {
+            id,
+            station_from: param.station_from,
+            station_to: param.station_to,
+            departure: param.departure,
+            weekdays: param.weekdays,
+            webhook_url: param.webhook_url,
+            transit_duration: param.transit_duration,
+            last_alert: None,
+        }
+    }
+}
+
+/// Commute subscriptions, kept in the local database.
+pub struct SubscriptionStore {
+    store: Store,
+}
+
+fn not_found(id: u64) -> AppError {
+    AppError {
+        message: Some(format!("Subscription {} was not found", id)),
+        cause: None,
+        error_type: AppErrorType::NotFoundError,
+        parameter: None,
+    }
+}
+
+impl SubscriptionStore {
+    pub fn new(store: Store) -> Self {
+        Self { store }
+    }
+
+    pub async fn list(&self) -> Result<Vec<Subscription>, AppError> {
+        self.store.run(|store| store.subscriptions()).await
+    }
+
+    pub async fn get(&self, id: u64) -> Result<Subscription, AppError> {
+        self.store
+            .run(move |store| store.subscription(id))
+            .await?
+            .ok_or_else(|| not_found(id))
+    }
+
+    pub async fn create(&self, param: SubscriptionParam) -> Result<Subscription, AppError> {
+        param.validate().await?;
+        let subscription = Subscription::new(0, param);
+        let stored = subscription.clone();
+        let id = self
+            .store
+            .run(move |store| store.insert_subscription(&stored))
+            .await?;
+        Ok(Subscription { id, ..subscription })
+    }
+
+    pub async fn update(
+        &self,
+        id: u64,
+        param: SubscriptionParam,
+    ) -> Result<Subscription, AppError> {
+        param.validate().await?;
+        let subscription = Subscription::new(id, param);
+        let stored = subscription.clone();
+        if !self
+            .store
+            .run(move |store| store.replace_subscription(&stored))
+            .await?
+        {
+            return Err(not_found(id));
+        }
+        Ok(subscription)
+    }
+
+    pub async fn delete(&self, id: u64) -> Result<(), AppError> {
+        if !self
+            .store
+            .run(move |store| store.delete_subscription(id))
+            .await?
+        {
+            return Err(not_found(id));
+        }
+        Ok(())
+    }
+
+    async fn record_alert(&self, id: u64, alert: AlertRecord) -> Result<(), AppError> {
+        self.store
+            .run(move |store| store.record_subscription_alert(id, &alert))
+            .await
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertKind {
+    Recommendation,
+    Update,
+}
+
+#[derive(Serialize)]
+struct CommuteAlert<'a> {
+    kind: AlertKind,
+    subscription_id: u64,
+    date: NaiveDate,
+    journey: &'a Journey,
+}
+
+fn alert_record(date: NaiveDate, journey: &Journey) -> AlertRecord {
+    AlertRecord {
+        date,
+        trains: journey
+            .legs
+            .iter()
+            .filter_map(|leg| Some((leg.train_id.clone()?, leg.board.time)))
+            .collect(),
+    }
+}
+
+async fn process_subscription(
+    upstream: &Upstream,
+    store: &SubscriptionStore,
+    subscription: Subscription,
+    concurrency: usize,
+) -> Result<(), AppError> {
+    let today = jakarta_now().date();
+    let station_from = Station::from_str(&subscription.station_from)?;
+    let station_to = Station::from_str(&subscription.station_to)?;
+    // Rows stored before transit durations were validated may be out of
+    // range; they are skipped rather than allowed to stop the scheduler.
+    let minutes = subscription.transit_duration.unwrap_or(0);
+    let transit_duration = Duration::try_minutes(minutes)
+        .filter(|_| (0..=MAX_TRANSIT_MINUTES).contains(&minutes))
+        .ok_or_else(|| AppError {
+            message: Some(format!("Transit duration {} is out of range", minutes)),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("transit_duration".into()),
+        })?;
+    let (journey, _) = choose_fastest_path(
+        upstream,
+        station_from,
+        station_to,
+        subscription.departure,
+        transit_duration,
+        &RouteConstraint::default(),
+        concurrency,
+    )
+    .await?;
+
+    let alert = alert_record(today, &journey);
+    let kind = match &subscription.last_alert {
+        Some(last_alert) if last_alert.date == today => {
+            if *last_alert == alert {
+                return Ok(());
+            }
+            AlertKind::Update
+        }
+        _ => AlertKind::Recommendation,
+    };
+    let (client, url) = webhook_client(&subscription.webhook_url).await?;
+    client
+        .post(url)
+        .json(&CommuteAlert {
+            kind,
+            subscription_id: subscription.id,
+            date: today,
+            journey: &journey,
+        })
+        .send()
+        .await?
+        .error_for_status()?;
+    store.record_alert(subscription.id, alert).await
+}
+
+/// Sends the recommended journey for every commute departing within the
+/// lead time, then keeps checking until departure and sends an update when
+/// the recommendation changes. Up to `concurrency` commutes are handled at
+/// once, so one slow webhook does not hold up the rest.
+pub async fn run_scheduler(
+    upstream: Arc<Upstream>,
+    store: Arc<SubscriptionStore>,
+    lead_minutes: i64,
+    concurrency: usize,
+) {
+    loop {
+        let now = jakarta_now();
+        match store.list().await {
+            Ok(subscriptions) => {
+                let due = subscriptions.into_iter().filter(|subscription| {
+                    let until_departure = subscription.departure - now.time();
+                    subscription.weekdays.contains(&now.weekday())
+                        && until_departure >= Duration::zero()
+                        && until_departure <= Duration::minutes(lead_minutes)
+                });
+                stream::iter(due)
+                    .for_each_concurrent(concurrency, |subscription| {
+                        let (upstream, store) = (&upstream, &store);
+                        async move {
+                            let id = subscription.id;
+                            if let Err(err) =
+                                process_subscription(upstream, store, subscription, concurrency)
+                                    .await
+                            {
+                                log::error!(
+                                    "Commute alert for subscription {} failed: {}",
+                                    id,
+                                    err.detail()
+                                );
+                            }
+                        }
+                    })
+                    .await;
+            }
+            Err(err) => log::error!("Failed to list subscriptions: {}", err.detail()),
+        }
+        rt::time::sleep(StdDuration::from_secs(SCHEDULER_TICK_SECONDS)).await;
+    }
+}
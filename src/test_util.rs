@@ -0,0 +1,21 @@
+use chrono::NaiveTime;
+
+use crate::{model::TrainSchedule, station::Station};
+
+/// Parses an `HH:MM` time.
+pub fn time(time: &str) -> NaiveTime {
+    NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+}
+
+/// Builds the schedule of train 1234 calling at each station at the given time.
+pub fn run(stops: &[(Station, &str)]) -> Vec<TrainSchedule> {
+    stops
+        .iter()
+        .map(|(station, time_est)| TrainSchedule {
+            train_id: "1234".into(),
+            station: *station,
+            unknown_station_id: None,
+            time_est: time(time_est),
+        })
+        .collect()
+}
@@ -0,0 +1,493 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::Arc,
+    time::Duration as StdDuration,
+};
+
+use actix_web::rt;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use futures::{stream, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use utoipa::ToSchema;
+
+use crate::{
+    error::AppError,
+    fetch::{fetch_upstream_station_schedule, fetch_upstream_train_schedule, Upstream},
+    model::{jakarta_now, TrainSchedule},
+    station::Station,
+    store::Store,
+    subscription::webhook_client,
+};
+
+/// Jakarta hour from which the day's snapshot is taken, before the first
+/// trains run and after KRL publishes the day's timetable.
+const SNAPSHOT_HOUR: u32 = 3;
+const SNAPSHOT_TICK_SECONDS: u64 = 600;
+const SNAPSHOT_MAX_BACKOFF_SECONDS: u64 = 6 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct TrainRoute {
+    pub train_id: String,
+    pub route_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct RouteNameChange {
+    pub train_id: String,
+    pub previous: String,
+    pub current: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct StopTimeShift {
+    pub train_id: String,
+    /// Station id of the stop.
+    pub station: String,
+    pub previous: NaiveTime,
+    pub current: NaiveTime,
+    /// Positive when the train now calls later.
+    pub shift_minutes: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct TrainStop {
+    pub train_id: String,
+    /// Station id of the stop.
+    pub station: String,
+    pub time: NaiveTime,
+}
+
+/// Differences between two daily timetable snapshots.
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct TimetableChange {
+    pub from_date: NaiveDate,
+    pub to_date: NaiveDate,
+    pub detected_at: NaiveDateTime,
+    pub trains_added: Vec<TrainRoute>,
+    pub trains_removed: Vec<TrainRoute>,
+    pub route_changes: Vec<RouteNameChange>,
+    pub time_shifts: Vec<StopTimeShift>,
+    pub stops_added: Vec<TrainStop>,
+    pub stops_removed: Vec<TrainStop>,
+}
+
+impl TimetableChange {
+    pub fn is_empty(&self) -> bool {
+        self.trains_added.is_empty()
+            && self.trains_removed.is_empty()
+            && self.route_changes.is_empty()
+            && self.time_shifts.is_empty()
+            && self.stops_added.is_empty()
+            && self.stops_removed.is_empty()
+    }
+}
+
+/// Keys each stop by station and occurrence, so loop services calling at a
+/// station twice compare first call with first call.
+fn keyed_stops(stops: &[TrainSchedule]) -> Vec<((String, usize), NaiveTime)> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    stops
+        .iter()
+        .map(|stop| {
+            let station = stop.station_id().to_string();
+            let occurrence = seen.entry(station.clone()).or_default();
+            *occurrence += 1;
+            ((station, *occurrence), stop.time_est)
+        })
+        .collect()
+}
+
+fn compare_stops(
+    train_id: &str,
+    previous: &[TrainSchedule],
+    current: &[TrainSchedule],
+    change: &mut TimetableChange,
+) {
+    let previous = keyed_stops(previous);
+    let current = keyed_stops(current);
+    let previous_times: HashMap<_, _> = previous.iter().cloned().collect();
+    let current_times: HashMap<_, _> = current.iter().cloned().collect();
+
+    for (key, time) in &current {
+        match previous_times.get(key) {
+            Some(previous_time) if previous_time != time => {
+                change.time_shifts.push(StopTimeShift {
+                    train_id: train_id.to_string(),
+                    station: key.0.clone(),
+                    previous: *previous_time,
+                    current: *time,
+                    shift_minutes: (*time - *previous_time).num_minutes(),
+                })
+            }
+            Some(_) => {}
+            None => change.stops_added.push(TrainStop {
+                train_id: train_id.to_string(),
+                station: key.0.clone(),
+                time: *time,
+            }),
+        }
+    }
+    for (key, time) in &previous {
+        if !current_times.contains_key(key) {
+            change.stops_removed.push(TrainStop {
+                train_id: train_id.to_string(),
+                station: key.0.clone(),
+                time: *time,
+            });
+        }
+    }
+}
+
+pub fn diff_snapshots(
+    store: &Store,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+) -> Result<TimetableChange, AppError> {
+    let previous_routes = store.train_routes(from_date)?;
+    let current_routes = store.train_routes(to_date)?;
+    let mut change = TimetableChange {
+        from_date,
+        to_date,
+        detected_at: jakarta_now(),
+        trains_added: vec![],
+        trains_removed: vec![],
+        route_changes: vec![],
+        time_shifts: vec![],
+        stops_added: vec![],
+        stops_removed: vec![],
+    };
+
+    let train_ids: BTreeSet<&String> = previous_routes
+        .keys()
+        .chain(current_routes.keys())
+        .collect();
+    for train_id in train_ids {
+        match (previous_routes.get(train_id), current_routes.get(train_id)) {
+            (None, Some(route_name)) => change.trains_added.push(TrainRoute {
+                train_id: train_id.clone(),
+                route_name: route_name.clone(),
+            }),
+            (Some(route_name), None) => change.trains_removed.push(TrainRoute {
+                train_id: train_id.clone(),
+                route_name: route_name.clone(),
+            }),
+            (Some(previous), Some(current)) if previous != current => {
+                change.route_changes.push(RouteNameChange {
+                    train_id: train_id.clone(),
+                    previous: previous.clone(),
+                    current: current.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+
+    let previous_stops = store.train_stops(from_date)?;
+    let current_stops = store.train_stops(to_date)?;
+    let mut common: Vec<&String> = current_stops
+        .keys()
+        .filter(|train_id| previous_stops.contains_key(*train_id))
+        .collect();
+    common.sort();
+    for train_id in common {
+        compare_stops(
+            train_id,
+            &previous_stops[train_id],
+            &current_stops[train_id],
+            &mut change,
+        );
+    }
+    Ok(change)
+}
+
+/// Harvests the full day's schedule of every station and of every train
+/// seen in them. Any station failing aborts the snapshot, since a partial
+/// one would show up as removed trains in the next diff.
+pub async fn take_snapshot(
+    upstream: &Upstream,
+    store: &Store,
+    date: NaiveDate,
+    concurrency: usize,
+) -> Result<(), AppError> {
+    let day_start = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+    let day_end = NaiveTime::from_hms_opt(23, 59, 0).unwrap();
+    let station_schedules = stream::iter(Station::iter())
+        .map(|station| async move {
+            fetch_upstream_station_schedule(upstream, station, day_start, day_end)
+                .await
+                .map(|(schedules, _)| (station, schedules))
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut train_ids = BTreeSet::new();
+    let mut station_count = 0;
+    for result in station_schedules {
+        let (station, schedules) = result?;
+        train_ids.extend(schedules.iter().map(|schedule| schedule.train_id.clone()));
+        store
+            .run(move |store| store.save_station_schedule(date, station, &schedules))
+            .await?;
+        station_count += 1;
+    }
+
+    let train_schedules = stream::iter(train_ids)
+        .map(|train_id| async move {
+            let result = fetch_upstream_train_schedule(upstream, &train_id).await;
+            (train_id, result)
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut train_count = 0;
+    for (train_id, result) in train_schedules {
+        match result {
+            Ok((schedules, _)) => {
+                store
+                    .run(move |store| store.save_train_schedule(date, &train_id, &schedules))
+                    .await?;
+                train_count += 1;
+            }
+            Err(err) => log::warn!(
+                "Snapshot of train {} on {} failed: {}",
+                train_id,
+                date,
+                err.detail()
+            ),
+        }
+    }
+    store
+        .run(move |store| store.record_snapshot(date, station_count, train_count))
+        .await
+}
+
+async fn snapshot_and_diff(
+    upstream: &Upstream,
+    store: &Store,
+    date: NaiveDate,
+    concurrency: usize,
+) -> Result<Option<TimetableChange>, AppError> {
+    take_snapshot(upstream, store, date, concurrency).await?;
+    store
+        .run(move |store| {
+            let Some(previous) = store.previous_snapshot(date)? else {
+                return Ok(None);
+            };
+            let change = diff_snapshots(store, previous, date)?;
+            if change.is_empty() {
+                return Ok(None);
+            }
+            store.save_timetable_change(&change)?;
+            Ok(Some(change))
+        })
+        .await
+}
+
+/// POSTs `change` to the webhook through the same guarded, time-limited
+/// client as subscription alerts.
+async fn deliver_change(webhook_url: &str, change: &TimetableChange) -> Result<(), AppError> {
+    let (client, url) = webhook_client(webhook_url).await?;
+    client
+        .post(url)
+        .json(change)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Doubles the wait after every consecutive failed snapshot so an upstream
+/// outage is not met with a full sweep every tick.
+fn snapshot_delay(failures: u32) -> StdDuration {
+    let backoff = SNAPSHOT_TICK_SECONDS.saturating_mul(1 << failures.min(16));
+    StdDuration::from_secs(backoff.min(SNAPSHOT_MAX_BACKOFF_SECONDS))
+}
+
+/// Takes one timetable snapshot per Jakarta day and records how it differs
+/// from the previous one, notifying `webhook_url` when it does. Changes the
+/// webhook did not accept are kept and retried every tick, oldest first,
+/// without holding back the next snapshot.
+pub async fn run_snapshotter(
+    upstream: Arc<Upstream>,
+    store: Store,
+    concurrency: usize,
+    webhook_url: Option<String>,
+) {
+    let mut undelivered: VecDeque<TimetableChange> = VecDeque::new();
+    let mut failures = 0;
+    loop {
+        let now = jakarta_now();
+        let today = now.date();
+        let due = now.hour() >= SNAPSHOT_HOUR
+            && !store
+                .run(move |store| store.has_snapshot(today))
+                .await
+                .unwrap_or(true);
+        if due {
+            match snapshot_and_diff(&upstream, &store, today, concurrency).await {
+                Ok(change) => {
+                    failures = 0;
+                    if webhook_url.is_some() {
+                        undelivered.extend(change);
+                    }
+                }
+                Err(err) => {
+                    failures += 1;
+                    log::error!(
+                        "Timetable snapshot for {} failed (attempt {}): {}",
+                        today,
+                        failures,
+                        err.detail()
+                    );
+                }
+            }
+        }
+        if let Some(webhook_url) = &webhook_url {
+            while let Some(change) = undelivered.front() {
+                if let Err(err) = deliver_change(webhook_url, change).await {
+                    log::warn!(
+                        "Delivering the timetable change of {} failed, retrying: {}",
+                        change.to_date,
+                        err.detail()
+                    );
+                    break;
+                }
+                undelivered.pop_front();
+            }
+        }
+        rt::time::sleep(snapshot_delay(failures)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::{
+        model::StationSchedule,
+        test_util::{run, time},
+    };
+
+    fn empty_change() -> TimetableChange {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        TimetableChange {
+            from_date: date,
+            to_date: date,
+            detected_at: jakarta_now(),
+            trains_added: vec![],
+            trains_removed: vec![],
+            route_changes: vec![],
+            time_shifts: vec![],
+            stops_added: vec![],
+            stops_removed: vec![],
+        }
+    }
+
+    fn unknown_stop(station_id: &str, time_est: &str) -> TrainSchedule {
+        TrainSchedule {
+            train_id: "1234".into(),
+            station: Station::Unknown,
+            unknown_station_id: Some(station_id.into()),
+            time_est: time(time_est),
+        }
+    }
+
+    #[test]
+    fn unknown_stations_are_compared_by_their_own_id() {
+        let previous = [unknown_stop("XA", "05:00"), unknown_stop("XB", "05:10")];
+        let current = [unknown_stop("XA", "05:00"), unknown_stop("XB", "05:15")];
+        let mut change = empty_change();
+        compare_stops("1234", &previous, &current, &mut change);
+        assert_eq!(change.time_shifts.len(), 1);
+        assert_eq!(change.time_shifts[0].station, "XB");
+        assert_eq!(change.time_shifts[0].shift_minutes, 5);
+        assert!(change.stops_added.is_empty());
+        assert!(change.stops_removed.is_empty());
+    }
+
+    #[test]
+    fn loop_services_compare_calls_in_order() {
+        let previous = run(&[
+            (Station::JAKK, "05:00"),
+            (Station::KPB, "05:20"),
+            (Station::JAKK, "05:40"),
+        ]);
+        let current = run(&[
+            (Station::JAKK, "05:00"),
+            (Station::KPB, "05:20"),
+            (Station::JAKK, "05:45"),
+        ]);
+        let mut change = empty_change();
+        compare_stops("1234", &previous, &current, &mut change);
+        assert_eq!(change.time_shifts.len(), 1);
+        assert_eq!(change.time_shifts[0].previous, time("05:40"));
+        assert_eq!(change.time_shifts[0].current, time("05:45"));
+    }
+
+    fn save_train(store: &Store, date: NaiveDate, route_name: &str, stops: &[TrainSchedule]) {
+        let departure = StationSchedule {
+            train_id: "1234".into(),
+            route_name: route_name.into(),
+            time_est: stops[0].time_est,
+        };
+        store
+            .save_station_schedule(date, stops[0].station, &[departure])
+            .unwrap();
+        store.save_train_schedule(date, "1234", stops).unwrap();
+    }
+
+    #[test]
+    fn diff_snapshots_reports_route_and_stop_changes() {
+        let store = Store::open(Path::new(":memory:")).unwrap();
+        let monday = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let tuesday = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        save_train(
+            &store,
+            monday,
+            "BOGOR-JAKARTAKOTA",
+            &run(&[(Station::BOO, "05:00"), (Station::DP, "05:30")]),
+        );
+        save_train(
+            &store,
+            tuesday,
+            "BOGOR-MANGGARAI",
+            &run(&[(Station::BOO, "05:00"), (Station::MRI, "05:50")]),
+        );
+
+        let change = diff_snapshots(&store, monday, tuesday).unwrap();
+        assert_eq!(change.route_changes.len(), 1);
+        assert_eq!(change.route_changes[0].current, "BOGOR-MANGGARAI");
+        assert_eq!(change.stops_added.len(), 1);
+        assert_eq!(change.stops_added[0].station, "MRI");
+        assert_eq!(change.stops_removed.len(), 1);
+        assert_eq!(change.stops_removed[0].station, "DP");
+        assert!(change.time_shifts.is_empty());
+        assert!(change.trains_added.is_empty() && change.trains_removed.is_empty());
+    }
+
+    #[test]
+    fn diff_snapshots_reports_added_and_removed_trains() {
+        let store = Store::open(Path::new(":memory:")).unwrap();
+        let monday = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let tuesday = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        save_train(
+            &store,
+            tuesday,
+            "BOGOR-JAKARTAKOTA",
+            &run(&[(Station::BOO, "05:00"), (Station::DP, "05:30")]),
+        );
+
+        let change = diff_snapshots(&store, monday, tuesday).unwrap();
+        assert_eq!(change.trains_added.len(), 1);
+        assert_eq!(change.trains_added[0].train_id, "1234");
+        assert!(change.stops_added.is_empty());
+
+        let change = diff_snapshots(&store, tuesday, monday).unwrap();
+        assert_eq!(change.trains_removed.len(), 1);
+        assert!(!change.is_empty());
+    }
+}
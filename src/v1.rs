@@ -0,0 +1,682 @@
+use std::collections::HashMap;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web, Error, HttpRequest, HttpResponse, Result,
+};
+use chrono::{NaiveDate, NaiveTime};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_derive::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    config::AppConfig,
+    error::{parse_param, AppError, AppErrorResponse, AppErrorType, Query},
+    fetch::{
+        fetch_distance, fetch_fare, fetch_station_schedule_with_warnings,
+        fetch_train_schedule_with_warnings, Upstream,
+    },
+    format::{tabular_response, OutputFormat},
+    line::TrainLine,
+    model::{
+        jakarta_now, service_date, DataWarning, Distance, Fare, FirstLastTrain, RouteAlternatives,
+        RouteProfile, StationScheduleList, StopTime, TrainSchedule, TransitRoutes,
+    },
+    pathfinder::{
+        choose_first_last_paths, choose_k_paths, generate_and_concat_train_schedule_path,
+        profile_paths, RankOption, RouteSort,
+    },
+    position::{fetch_line_positions, fetch_train_position, LinePositions, TrainPosition},
+    route::{parse_route_constraint, parse_transit_duration},
+    station::Station,
+};
+
+/// Date the unversioned endpoints were deprecated, as RFC 9745 wants it.
+const DEPRECATED_SINCE: &str = "@1792281600";
+/// Most alternatives `/v1/journeys` and `/routes` rank in one request.
+const MAX_JOURNEYS: usize = 20;
+
+#[derive(Serialize, ToSchema)]
+pub struct StationResource {
+    pub id: String,
+    pub name: String,
+    pub lines: Vec<TrainLine>,
+    pub transit_station: bool,
+}
+
+impl From<Station> for StationResource {
+    fn from(station: Station) -> Self {
+        Self {
+            id: station.id().to_string(),
+            name: station.name().to_string(),
+            lines: station.line(),
+            transit_station: station.is_transit_station(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LineResource {
+    pub id: String,
+    pub name: String,
+    pub stations: Vec<Station>,
+}
+
+impl From<TrainLine> for LineResource {
+    fn from(line: TrainLine) -> Self {
+        Self {
+            id: line.id().to_string(),
+            name: line.name().to_string(),
+            stations: Station::iter()
+                .filter(|station| station.line().contains(&line))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TrainResource {
+    pub id: String,
+    pub stops: Vec<StopTime>,
+    /// Upstream rows that were skipped or patched, and whether the stops
+    /// came from the store.
+    pub warnings: Vec<DataWarning>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct StationsParam {
+    line_id: Option<String>,
+    transit_station_only: Option<bool>,
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    params(StationsParam),
+    responses(
+        (status = 200, description = "OK", body = Vec<StationResource>),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/stations")]
+async fn list_stations(req: Query<StationsParam>) -> Result<HttpResponse, AppError> {
+    let line = match &req.line_id {
+        Some(line) => Some(parse_param(line, "line-id")?),
+        None => None,
+    };
+    let transit_station_only = req.transit_station_only.unwrap_or_default();
+    let stations = Station::iter()
+        .filter(|station| line.is_none_or(|line| station.line().contains(&line)))
+        .filter(|station| !transit_station_only || station.is_transit_station())
+        .map(StationResource::from)
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(stations))
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    params(("id" = String, Path, description = "Station id, such as BOO")),
+    responses(
+        (status = 200, description = "OK", body = StationResource),
+        (status = 400, description = "Unknown station", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/stations/{id}")]
+async fn get_station(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let station: Station = parse_param(&id, "id")?;
+    Ok(HttpResponse::Ok().json(StationResource::from(station)))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct DeparturesParam {
+    time_from: Option<NaiveTime>,
+    time_to: Option<NaiveTime>,
+    format: Option<OutputFormat>,
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    params(("id" = String, Path, description = "Station id, such as BOO"), DeparturesParam),
+    responses(
+        (status = 200, description = "OK", content(
+            (StationScheduleList = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        )),
+        (status = 400, description = "Unknown station", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/stations/{id}/departures")]
+async fn list_station_departures(
+    id: web::Path<String>,
+    req: Query<DeparturesParam>,
+    upstream: web::Data<Upstream>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let station = parse_param(&id, "id")?;
+    let time_from = match req.time_from {
+        Some(time) => time,
+        None => NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+    };
+    let time_to = match req.time_to {
+        Some(time) => time,
+        None => NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+    };
+    let (schedules, warnings) =
+        fetch_station_schedule_with_warnings(&upstream, station, time_from, time_to).await?;
+    let format = OutputFormat::negotiate(req.format, &http_req);
+    let json = StationScheduleList {
+        schedules,
+        warnings,
+    };
+    Ok(tabular_response(format, json, |json| json.schedules))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+struct FormatParam {
+    format: Option<OutputFormat>,
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    params(("id" = String, Path, description = "Train id"), FormatParam),
+    responses(
+        (status = 200, description = "OK", content(
+            (TrainResource = "application/json"),
+            (String = "text/csv"),
+            (String = "application/x-ndjson")
+        )),
+        (status = 404, description = "Unknown train", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/trains/{id}")]
+async fn get_train(
+    id: web::Path<String>,
+    req: Query<FormatParam>,
+    upstream: web::Data<Upstream>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let (schedules, warnings): (Vec<TrainSchedule>, _) =
+        fetch_train_schedule_with_warnings(&upstream, &id).await?;
+    let train = TrainResource {
+        id: id.into_inner(),
+        stops: schedules
+            .iter()
+            .map(|schedule| StopTime {
+                station: schedule.station,
+                time: schedule.time_est,
+            })
+            .collect(),
+        warnings,
+    };
+    let format = OutputFormat::negotiate(req.format, &http_req);
+    Ok(tabular_response(format, train, |_| schedules))
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    params(("id" = String, Path, description = "Train id")),
+    responses(
+        (status = 200, description = "OK", body = TrainPosition),
+        (status = 404, description = "Unknown train", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/trains/{id}/position")]
+async fn get_train_position(
+    id: web::Path<String>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let position = fetch_train_position(&upstream, &id, jakarta_now().time()).await?;
+    Ok(HttpResponse::Ok().json(position))
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    responses((status = 200, description = "OK", body = Vec<LineResource>))
+)]
+#[get("/lines")]
+async fn list_lines() -> Result<HttpResponse, AppError> {
+    let lines = TrainLine::iter()
+        .map(LineResource::from)
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(lines))
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    params(("id" = String, Path, description = "Line id, such as B")),
+    responses(
+        (status = 200, description = "OK", body = LineResource),
+        (status = 400, description = "Unknown line", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/lines/{id}")]
+async fn get_line(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let line: TrainLine = parse_param(&id, "id")?;
+    Ok(HttpResponse::Ok().json(LineResource::from(line)))
+}
+
+#[utoipa::path(
+    context_path = "/v1",
+    tag = "v1",
+    params(("id" = String, Path, description = "Line id, such as B")),
+    responses(
+        (status = 200, description = "Running trains, with a warning for every station or train left out", body = LinePositions),
+        (status = 400, description = "Unknown line", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+#[get("/lines/{id}/positions")]
+async fn list_line_positions(
+    id: web::Path<String>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let line = parse_param(&id, "id")?;
+    let positions =
+        fetch_line_positions(&upstream, line, jakarta_now(), config.fetch_concurrency).await?;
+    Ok(HttpResponse::Ok().json(positions))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+pub(crate) struct StationPairParam {
+    station_from: String,
+    station_to: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/fares",
+    context_path = "/v1",
+    tag = "v1",
+    params(StationPairParam),
+    responses(
+        (status = 200, description = "OK", body = Fare),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+pub(crate) async fn get_fare(
+    req: Query<StationPairParam>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let fare = fetch_fare(&upstream, station_from, station_to).await?;
+    Ok(HttpResponse::Ok().json(fare))
+}
+
+#[utoipa::path(
+    get,
+    path = "/distances",
+    context_path = "/v1",
+    tag = "v1",
+    params(StationPairParam),
+    responses(
+        (status = 200, description = "OK", body = Distance),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+pub(crate) async fn get_distance(
+    req: Query<StationPairParam>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let distance = fetch_distance(&upstream, station_from, station_to).await?;
+    Ok(HttpResponse::Ok().json(distance))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+pub(crate) struct JourneysParam {
+    station_from: String,
+    station_to: String,
+    time_from: NaiveTime,
+    transit_duration: Option<i64>,
+    k: Option<usize>,
+    sort: Option<RouteSort>,
+    via: Option<String>,
+    avoid_stations: Option<String>,
+    avoid_lines: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/journeys",
+    context_path = "/v1",
+    tag = "v1",
+    params(JourneysParam),
+    responses(
+        (status = 200, description = "OK", body = RouteAlternatives),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+pub(crate) async fn list_journeys(
+    req: Query<JourneysParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let duration = parse_transit_duration(req.transit_duration)?;
+    let k = req.k.unwrap_or(5);
+    if k > MAX_JOURNEYS {
+        return Err(AppError {
+            message: Some(format!("k cannot be more than {}", MAX_JOURNEYS)),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("k".into()),
+        });
+    }
+    let constraint = parse_route_constraint(&req.via, &req.avoid_stations, &req.avoid_lines)?;
+    let (journeys, skipped_paths) = choose_k_paths(
+        &upstream,
+        station_from,
+        station_to,
+        req.time_from,
+        duration,
+        &constraint,
+        RankOption {
+            k,
+            sort: req.sort.unwrap_or_default(),
+        },
+        config.fetch_concurrency,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(RouteAlternatives {
+        journeys,
+        skipped_paths,
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+pub(crate) struct AllJourneysParam {
+    station_from: String,
+    station_to: String,
+    /// Defaults to the current time in Jakarta.
+    time_from: Option<NaiveTime>,
+    transit_duration: Option<i64>,
+    via: Option<String>,
+    avoid_stations: Option<String>,
+    avoid_lines: Option<String>,
+}
+
+/// The earliest journey along every candidate path, unranked.
+#[utoipa::path(
+    get,
+    path = "/journeys/all",
+    context_path = "/v1",
+    tag = "v1",
+    params(AllJourneysParam),
+    responses(
+        (status = 200, description = "OK", body = TransitRoutes),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+pub(crate) async fn list_all_journeys(
+    req: Query<AllJourneysParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let time_from = req.time_from.unwrap_or_else(|| jakarta_now().time());
+    let duration = parse_transit_duration(req.transit_duration)?;
+    let constraint = parse_route_constraint(&req.via, &req.avoid_stations, &req.avoid_lines)?;
+    let (journeys, skipped_paths) = generate_and_concat_train_schedule_path(
+        &upstream,
+        station_from,
+        station_to,
+        time_from,
+        duration,
+        &constraint,
+        config.fetch_concurrency,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(TransitRoutes {
+        journeys,
+        skipped_paths,
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+pub(crate) struct JourneyProfileParam {
+    station_from: String,
+    station_to: String,
+    time_from: NaiveTime,
+    time_to: NaiveTime,
+    transit_duration: Option<i64>,
+    via: Option<String>,
+    avoid_stations: Option<String>,
+    avoid_lines: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/journeys/profile",
+    context_path = "/v1",
+    tag = "v1",
+    params(JourneyProfileParam),
+    responses(
+        (status = 200, description = "OK", body = RouteProfile),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+pub(crate) async fn get_journey_profile(
+    req: Query<JourneyProfileParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let duration = parse_transit_duration(req.transit_duration)?;
+    let constraint = parse_route_constraint(&req.via, &req.avoid_stations, &req.avoid_lines)?;
+    let (journeys, skipped_paths) = profile_paths(
+        &upstream,
+        station_from,
+        station_to,
+        req.time_from,
+        req.time_to,
+        duration,
+        &constraint,
+        config.fetch_concurrency,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(RouteProfile {
+        journeys,
+        skipped_paths,
+    }))
+}
+
+/// Today's service date, rejecting any other requested date.
+fn check_service_date(date: Option<NaiveDate>) -> Result<NaiveDate, AppError> {
+    let today = service_date(jakarta_now());
+    if date.is_some_and(|date| date != today) {
+        return Err(AppError {
+            message: Some(format!(
+                "date must be today, {}; KRL only publishes the current timetable",
+                today
+            )),
+            cause: None,
+            error_type: AppErrorType::InvalidRequestParameter,
+            parameter: Some("date".into()),
+        });
+    }
+    Ok(today)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "kebab-case")]
+#[into_params(parameter_in = Query)]
+pub(crate) struct FirstLastParam {
+    station_from: String,
+    station_to: String,
+    /// Service day to answer for. Only today's timetable is known, so any
+    /// other date is rejected.
+    date: Option<NaiveDate>,
+    transit_duration: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/journeys/first-last",
+    context_path = "/v1",
+    tag = "v1",
+    params(FirstLastParam),
+    responses(
+        (status = 200, description = "OK", body = FirstLastTrain),
+        (status = 400, description = "Invalid parameter", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "No route", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 502, description = "Upstream unavailable or returned bad data", body = AppErrorResponse, content_type = "application/problem+json"),
+        (status = 504, description = "Upstream timed out", body = AppErrorResponse, content_type = "application/problem+json")
+    )
+)]
+pub(crate) async fn get_first_last_journeys(
+    req: Query<FirstLastParam>,
+    config: web::Data<AppConfig>,
+    upstream: web::Data<Upstream>,
+) -> Result<HttpResponse, AppError> {
+    let station_from = parse_param(&req.station_from, "station-from")?;
+    let station_to = parse_param(&req.station_to, "station-to")?;
+    let today = check_service_date(req.date)?;
+    let duration = parse_transit_duration(req.transit_duration)?;
+    let (first_train, last_train, skipped_paths) = choose_first_last_paths(
+        &upstream,
+        station_from,
+        station_to,
+        duration,
+        config.fetch_concurrency,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(FirstLastTrain {
+        date: today,
+        first_train,
+        last_train,
+        skipped_paths,
+    }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/v1")
+            .service(list_stations)
+            .service(list_station_departures)
+            .service(get_station)
+            .service(get_train)
+            .service(get_train_position)
+            .service(list_lines)
+            .service(get_line)
+            .service(list_line_positions)
+            .route("/fares", web::get().to(get_fare))
+            .route("/distances", web::get().to(get_distance))
+            .route("/journeys", web::get().to(list_journeys))
+            .route("/journeys/all", web::get().to(list_all_journeys))
+            .route("/journeys/profile", web::get().to(get_journey_profile))
+            .route(
+                "/journeys/first-last",
+                web::get().to(get_first_last_journeys),
+            ),
+    );
+}
+
+/// Query parameter `name`, decoded and percent-encoded again as a path
+/// segment so that it cannot break out of the `Link` header.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let query = web::Query::<HashMap<String, String>>::from_query(query).ok()?;
+    query
+        .get(name)
+        .map(|value| utf8_percent_encode(value, NON_ALPHANUMERIC).to_string())
+}
+
+/// The `/v1` replacement of an unversioned endpoint, filled in from the
+/// request's own query where the new path needs it.
+fn successor(path: &str, query: &str) -> Option<String> {
+    match path {
+        "/station-list" => Some("/v1/stations".into()),
+        "/line-list" => Some("/v1/lines".into()),
+        "/station-schedule" => query_param(query, "station")
+            .map(|station| format!("/v1/stations/{}/departures", station)),
+        "/train-schedule" => {
+            query_param(query, "train-id").map(|train_id| format!("/v1/trains/{}", train_id))
+        }
+        "/train-position" => query_param(query, "train-id")
+            .map(|train_id| format!("/v1/trains/{}/position", train_id)),
+        "/train-positions" => {
+            query_param(query, "line-id").map(|line_id| format!("/v1/lines/{}/positions", line_id))
+        }
+        "/fare" => Some("/v1/fares".into()),
+        "/distance" => Some("/v1/distances".into()),
+        "/get-fastest-route" | "/routes" => Some("/v1/journeys".into()),
+        "/get-all-transit-route" => Some("/v1/journeys/all".into()),
+        "/get-route-profile" => Some("/v1/journeys/profile".into()),
+        "/first-last-train" => Some("/v1/journeys/first-last".into()),
+        _ => None,
+    }
+}
+
+/// Marks responses of unversioned endpoints that have a `/v1` replacement
+/// with `Deprecation` and a `Link` to the successor.
+pub async fn deprecation_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let successor = successor(req.path(), req.query_string());
+    let mut res = next.call(req).await?;
+    if let Some(successor) = successor {
+        let headers = res.headers_mut();
+        headers.insert(
+            HeaderName::from_static("deprecation"),
+            HeaderValue::from_static(DEPRECATED_SINCE),
+        );
+        if let Ok(link) =
+            HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+        {
+            headers.insert(HeaderName::from_static("link"), link);
+        }
+    }
+    Ok(res)
+}